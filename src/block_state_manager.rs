@@ -1,25 +1,113 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{Debug, Display, Formatter};
 use std::hash::Hash;
-use std::sync::{Arc, Mutex, RwLock};
-use sov_first_read_last_write_cache::{CacheKey, CacheValue};
+use std::path::Path;
+use std::sync::{Arc, LockResult, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak};
+use std::sync::mpsc::{channel, Receiver, Sender};
 use crate::db::Storage;
-use crate::state::{FrozenSnapshot};
-use crate::types::{Key, ReadOnlyLock, Value};
+use crate::journal::{FileJournal, Journal, JournalCodec, JournalRecord};
+use crate::metrics::{Metrics, NoopMetrics};
+use crate::types::ReadOnlyLock;
 
+/// Number of the latest finalized blocks, which are recognized when they are sent again.
+/// Older ones are not pending anymore either, so they are reported as unknown.
+pub const FINALIZED_BLOCKS_KEPT: usize = 1024;
+
+/// Journal is rewritten with only pending blocks, once this many records have been appended to it,
+/// so records of finalized and discarded blocks do not pile up.
+pub const JOURNAL_REWRITE_RECORDS: usize = 1024;
+
+/// Default identity of snapshots: sequence number issued by the manager.
 pub type SnapshotId = u64;
 
+/// How [`BlockStateManager`] issues id of the snapshot for newly registered block.
+pub trait SnapshotIdentity<Bh>: Sized {
+    fn next_id(latest: Option<&Self>, block_hash: &Bh) -> Self;
+
+    /// Block hash the id has been issued for, if id is derived from it.
+    /// Manager keeps mapping from ids to block hashes only for ids, which return `None`.
+    fn block_hash(&self) -> Option<&Bh> {
+        None
+    }
+}
+
+/// Block hash types, which snapshots can be identified by sequential [`SnapshotId`] for.
+/// [`SnapshotId`] itself is not one, as it is identified by itself.
+pub trait SequencedBlockHash {}
+
+impl SequencedBlockHash for String {}
+
+impl SequencedBlockHash for Vec<u8> {}
+
+impl<const N: usize> SequencedBlockHash for [u8; N] {}
+
+impl<Bh: SequencedBlockHash> SnapshotIdentity<Bh> for SnapshotId {
+    fn next_id(latest: Option<&Self>, _block_hash: &Bh) -> Self {
+        latest.map_or(1, |id| id + 1)
+    }
+}
+
+/// Allows snapshots to be keyed by block hash directly, e.g. `FrozenSnapshot<BlockHash>`.
+impl<Bh: Clone> SnapshotIdentity<Bh> for Bh {
+    fn next_id(_latest: Option<&Self>, block_hash: &Bh) -> Self {
+        block_hash.clone()
+    }
+
+    fn block_hash(&self) -> Option<&Bh> {
+        Some(self)
+    }
+}
+
 /// Snapshot of the state
 /// It can give a value that has been written/created on given state
 /// [`BlockStateManager`] suppose to operate over those
 pub trait Snapshot {
-    type Key;
+    type Key: Eq + Hash + Clone;
     type Value: Clone;
+    /// [`SnapshotId`] or block hash itself, see [`SnapshotIdentity`]
+    type Id: Eq + Hash + Clone + Debug;
 
-    /// Get own value, value from its own cache
-    fn get_value(&self, key: &Self::Key) -> Option<Self::Value>;
+    /// Get own value, value from its own cache.
+    /// `None` if key has not been written in this snapshot, `Some(None)` if it has been deleted.
+    fn get_value(&self, key: &Self::Key) -> Option<Option<Self::Value>>;
 
     /// Helper method for mapping
-    fn get_id(&self) -> SnapshotId;
+    fn get_id(&self) -> Self::Id;
+
+    /// Cheap check, that allows to skip snapshot.
+    /// `false` means that key is definitely not in the snapshot, `true` means that it might be.
+    fn may_contain(&self, _key: &Self::Key) -> bool {
+        true
+    }
+
+    /// Number of keys written in this snapshot
+    fn writes_count(&self) -> usize;
+
+    /// Approximate memory taken by written keys and values
+    fn size_in_bytes(&self) -> usize;
+
+    /// Keys written in this snapshot, including deleted ones.
+    /// Order should not depend on the run, as keys are passed to [`ForkTreeEvent::Finalized`].
+    fn written_keys(&self) -> Vec<Self::Key>;
+
+    /// Token of the block registration, snapshot has been executed for, see [`TreeQuery::branch_token`].
+    /// It is dead, if block has been pruned, even if the same block has been registered again since.
+    fn branch_token(&self) -> BranchToken;
+}
+
+/// Ties snapshot to the manager and to the block registration, it has been executed for.
+/// Both references are weak, so token never keeps either of them alive.
+#[derive(Debug, Clone, Default)]
+pub struct BranchToken {
+    manager: Weak<()>,
+    registration: Weak<()>,
+}
+
+impl BranchToken {
+    /// Block has been pruned or its manager has been dropped.
+    pub fn is_dead(&self) -> bool {
+        self.registration.strong_count() == 0
+    }
 }
 
 
@@ -29,10 +117,44 @@ pub struct TreeQuery<P, Q>
         Q: QueryParents,
 
 {
-    pub id: SnapshotId,
+    pub id: <Q::Snapshot as Snapshot>::Id,
     pub db: Arc<Mutex<P>>,
     // pub manager: ReadOnlyLock<BlockStateManager<P, S, Bh>>,
     pub manager: ReadOnlyLock<Q>,
+    // Ancestors resolved on first read, together with generation of the tree they were resolved at.
+    // Behind a mutex, so query can be shared between threads, it is never contended otherwise
+    lineage: Mutex<Option<(u64, Q::Lineage)>>,
+    // Manager holds strong reference while block is pending, and drops it when block is pruned
+    branch: BranchToken,
+    metrics: Arc<dyn Metrics>,
+}
+
+/// Reading through [`TreeQuery`] can fail, if its block is no longer in the fork tree.
+#[derive(Debug, PartialEq, Eq)]
+pub enum QueryError<Id = SnapshotId> {
+    /// Block has been removed from the tree, most likely because competing fork has been finalized.
+    /// Values of its parents are no longer reliable.
+    Orphaned(Id),
+}
+
+impl<Id: Debug> Display for QueryError<Id> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryError::Orphaned(id) => write!(f, "snapshot {:?} has been orphaned", id),
+        }
+    }
+}
+
+impl<Id: Debug> std::error::Error for QueryError<Id> {}
+
+/// Layer, which has answered read through [`TreeQuery`].
+/// Values of pending snapshots are proven against output of their blocks, not against the storage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValueSource<Id = SnapshotId> {
+    /// Pending ancestor, which has written the value
+    Snapshot(Id),
+    /// Committed storage
+    Storage,
 }
 
 
@@ -42,16 +164,40 @@ impl<P, Q> TreeQuery<P, Q>
         Q: QueryParents,
 
 {
-    pub fn new(id: SnapshotId, db: Arc<Mutex<P>>, manager: ReadOnlyLock<Q>) -> Self {
+    pub fn new(id: <Q::Snapshot as Snapshot>::Id, db: Arc<Mutex<P>>, manager: ReadOnlyLock<Q>, branch: BranchToken) -> Self {
         Self {
             id,
             db,
             manager,
+            lineage: Mutex::new(None),
+            branch,
+            metrics: Arc::new(NoopMetrics),
         }
     }
 
-    pub fn get_id(&self) -> SnapshotId {
-        self.id
+    pub fn with_metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Metrics of the manager, this query has been issued by.
+    pub fn metrics(&self) -> &dyn Metrics {
+        self.metrics.as_ref()
+    }
+
+    pub fn get_id(&self) -> <Q::Snapshot as Snapshot>::Id {
+        self.id.clone()
+    }
+
+    /// Snapshot produced on top of this query carries it, so manager can tell it belongs to the current registration of the block.
+    pub fn branch_token(&self) -> BranchToken {
+        self.branch.clone()
+    }
+
+    /// Block has been pruned from the fork tree, so execution on top of this query can be aborted.
+    /// Block cannot be finalized before its snapshot is produced, so it means it has been discarded.
+    pub fn is_cancelled(&self) -> bool {
+        self.branch.is_dead()
     }
 }
 
@@ -61,15 +207,29 @@ impl<P, Q> TreeQuery<P, Q>
         P: Storage<Key=<Q::Snapshot as Snapshot>::Key, Value=<Q::Snapshot as Snapshot>::Value>,
         Q: QueryParents,
 {
-    pub fn get_value_from_cache_layers(&self, key: &<Q::Snapshot as Snapshot>::Key) -> Option<<Q::Snapshot as Snapshot>::Value> {
+    /// Value as the block sees it, together with the layer it has been found in.
+    #[allow(clippy::type_complexity)]
+    pub fn get_value_from_cache_layers(&self, key: &<Q::Snapshot as Snapshot>::Key) -> Result<(Option<<Q::Snapshot as Snapshot>::Value>, ValueSource<<Q::Snapshot as Snapshot>::Id>), QueryError<<Q::Snapshot as Snapshot>::Id>> {
         let manager = self.manager.read().unwrap();
-        let value_from_cache = manager.get_value_recursively(&self.id, key);
-        if value_from_cache.is_some() {
-            return value_from_cache;
+        // Checked under the lock, so block cannot be pruned in the middle of the read
+        if self.is_cancelled() {
+            return Err(QueryError::Orphaned(self.id.clone()));
+        }
+        let generation = manager.generation();
+        let mut lineage = self.lineage.lock().unwrap();
+        let lineage = match lineage.as_mut() {
+            Some((lineage_generation, lineage)) if *lineage_generation == generation => lineage,
+            _ => &lineage.insert((generation, manager.lineage(&self.id))).1,
+        };
+        // Deleted in one of the parents, so whatever storage has is outdated
+        if let Some(hit) = manager.get_value_along(lineage, key) {
+            self.metrics.cache_layer_hit(hit.depth);
+            return Ok((hit.value, ValueSource::Snapshot(hit.snapshot_id)));
         }
 
+        self.metrics.storage_fallback();
         let db = self.db.lock().unwrap();
-        db.get(key)
+        Ok((db.get(key), ValueSource::Storage))
     }
 }
 
@@ -77,8 +237,6 @@ impl<P, Q> TreeQuery<P, Q>
 pub struct BlockStateManager<P: Storage, S: Snapshot, Bh> {
     // Storage
     db: Arc<Mutex<P>>,
-    // Helpers
-    self_ref: Option<Arc<RwLock<BlockStateManager<P, S, Bh>>>>,
 
     snapshots: HashMap<Bh, S>,
 
@@ -89,17 +247,233 @@ pub struct BlockStateManager<P: Storage, S: Snapshot, Bh> {
     blocks_to_parent: HashMap<Bh, Bh>,
 
     // Helper mappings
-    latest_snapshot_id: SnapshotId,
-    snapshot_id_to_block_hash: HashMap<SnapshotId, Bh>,
+    latest_snapshot_id: Option<S::Id>,
+    // Empty, when snapshots are identified by block hash, see `SnapshotIdentity::block_hash`
+    snapshot_id_to_block_hash: HashMap<S::Id, Bh>,
+    // Every pending block, including ones which snapshot has not been added yet
+    registrations: HashMap<Bh, Registration<S::Id>>,
+    // Weak references are carried by branch tokens, so snapshots of other managers are told apart, even dropped ones
+    identity: Arc<()>,
+    // Last committed block, so it can be used as parent after all pending blocks are gone
+    last_finalized_block: Option<Bh>,
+    // Recently committed blocks, oldest first, so resent blocks are not executed and committed again
+    finalized_window: VecDeque<Bh>,
+    finalized_blocks: HashSet<Bh>,
+    // Incremented each time blocks are removed from the tree or compacted, so cached lineages can be invalidated
+    generation: u64,
+
+    // Compacted linear chains: newest block of the chain -> merged writes of the whole chain
+    #[allow(clippy::type_complexity)]
+    compacted_layers: HashMap<Bh, CompactedLayer<S::Key, S::Value, Bh, S::Id>>,
+    // Block -> newest block of the compacted chain it belongs to
+    compacted_blocks: HashMap<Bh, Bh>,
+
+    // Pending blocks are not accepted above these
+    limits: PendingLimits,
+    // Sum of sizes of all pending snapshots and compacted layers
+    pending_bytes: usize,
+
+    // Persists pending tree, if manager has been created with `recover`
+    journal: Option<Box<dyn Journal<Bh, S>>>,
+    // Last journal write has failed, so journal is behind the tree and nothing is written until it is repaired
+    journal_failure: Option<std::io::ErrorKind>,
+    // Records in the journal, including ones of blocks, which are not pending anymore
+    journal_records: usize,
+    metrics: Arc<dyn Metrics>,
+    // Receivers of lifecycle events, dropped ones are removed on the next event
+    #[allow(clippy::type_complexity)]
+    subscribers: Vec<Sender<ForkTreeEvent<Bh, S::Id, S::Key>>>,
+}
+
+/// Pending block as it has been registered by [`BlockStateManager::register_block`].
+#[derive(Debug)]
+struct Registration<Id> {
+    snapshot_id: Id,
+    // Weak references are given to its TreeQuery and then to its snapshot.
+    // Snapshot with dead token has been executed for a pruned block
+    branch_token: Arc<()>,
+}
+
+/// Lifecycle of blocks in the fork tree, sent to subscribers in order changes have been made.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ForkTreeEvent<Bh, Id, K> {
+    SnapshotAdded {
+        block: Bh,
+        id: Id,
+    },
+    /// Snapshot of the block has been committed to the storage, keys are in [`Snapshot::written_keys`] order
+    Finalized {
+        block: Bh,
+        written_keys: Vec<K>,
+    },
+    /// Blocks have been removed without being committed, parents go before children.
+    /// Follows [`ForkTreeEvent::Finalized`], if it caused competing forks to be pruned.
+    Discarded {
+        blocks: Vec<Bh>,
+    },
+}
+
+/// Bounds of the pending tree, so stalled finality does not lead to unbounded memory growth.
+/// `None` means no limit.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PendingLimits {
+    /// Blocks registered in the tree, including ones which are still being executed
+    pub max_blocks: Option<usize>,
+    /// Total size of pending snapshots, see [`Snapshot::size_in_bytes`], and of their values copied into compacted chains
+    pub max_bytes: Option<usize>,
+}
+
+/// Current usage of the pending tree, measured same way as [`PendingLimits`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PendingUsage {
+    pub blocks: usize,
+    pub bytes: usize,
+}
+
+impl PendingLimits {
+    fn is_exceeded_by(&self, usage: &PendingUsage) -> bool {
+        self.max_blocks.is_some_and(|max_blocks| usage.blocks >= max_blocks)
+            || self.max_bytes.is_some_and(|max_bytes| usage.bytes >= max_bytes)
+    }
+}
+
+/// Writes of several consecutive snapshots merged into a single map.
+/// Original snapshots are kept, so each block can still be finalized or read separately.
+/// Merged values are only valid for reads, that enter the chain from its newest block.
+#[derive(Debug)]
+struct CompactedLayer<K, V, Bh, Id> {
+    // From the newest to the oldest
+    blocks: Vec<Bh>,
+    // Last write of each key in the chain
+    values: HashMap<K, CompactedValue<V, Id>>,
+    // Share of snapshot sizes, that merged values have been copied from, see `copied_bytes`
+    bytes: usize,
+}
+
+#[derive(Debug)]
+struct CompactedValue<V, Id> {
+    // `None` for deleted keys
+    value: Option<V>,
+    // Snapshot, which has written the value last
+    written_by: Id,
+    // Position of the snapshot in the chain, 0 for the newest block
+    offset: usize,
+}
+
+/// Approximate size of given number of snapshot values, copied into [`CompactedLayer`].
+/// Keys and values are opaque, so they are assumed to be of the snapshot's average size.
+fn copied_bytes<S: Snapshot>(snapshot: &S, copied_values: usize) -> usize {
+    match snapshot.writes_count() {
+        0 => 0,
+        writes_count => snapshot.size_in_bytes() * copied_values / writes_count,
+    }
+}
+
+impl<K, V, Bh, Id> CompactedLayer<K, V, Bh, Id> {
+    fn oldest_block(&self) -> &Bh {
+        self.blocks.last().expect("compacted layer cannot be empty")
+    }
+}
+
+/// Misuse of the fork tree, reported instead of panicking,
+/// so misbehaving DA adapter cannot bring the node down.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BlockStateManagerError<Bh, Id = SnapshotId> {
+    /// Block has never been requested or has been already discarded
+    UnknownBlock(Bh),
+    /// Snapshot reference has been already requested for this block
+    DuplicateBlock(Bh),
+    /// Snapshot id has not been issued by this manager
+    AlienSnapshot(Id),
+    /// Block of the snapshot has been pruned while it was executed
+    CancelledSnapshot(Id),
+    /// Block has been already committed to the storage
+    AlreadyFinalized(Bh),
+    /// Parent block is neither pending nor finalized
+    UnknownParent(Bh),
+    /// Block has pending parent, which should be finalized first
+    NonRootFinalization(Bh),
+    /// Block is known, but its snapshot has not been added yet
+    MissingSnapshot(Bh),
+    /// Pending tree has reached its [`PendingLimits`], new blocks can be added after finalization catches up
+    BackPressure(PendingUsage),
+}
+
+impl<Bh: Debug, Id: Debug> Display for BlockStateManagerError<Bh, Id> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockStateManagerError::UnknownBlock(bh) => write!(f, "unknown block {:?}", bh),
+            BlockStateManagerError::DuplicateBlock(bh) => write!(f, "block {:?} has been already added", bh),
+            BlockStateManagerError::AlienSnapshot(id) => write!(f, "snapshot {:?} has not been issued by this manager", id),
+            BlockStateManagerError::CancelledSnapshot(id) => write!(f, "snapshot {:?} has been cancelled, as its block has been pruned", id),
+            BlockStateManagerError::AlreadyFinalized(bh) => write!(f, "block {:?} has been already finalized", bh),
+            BlockStateManagerError::UnknownParent(bh) => write!(f, "unknown parent block {:?}", bh),
+            BlockStateManagerError::NonRootFinalization(bh) => write!(f, "block {:?} has pending parent and cannot be finalized", bh),
+            BlockStateManagerError::MissingSnapshot(bh) => write!(f, "snapshot for block {:?} has not been added", bh),
+            BlockStateManagerError::BackPressure(usage) => write!(f, "too many pending blocks: {} blocks, {} bytes", usage.blocks, usage.bytes),
+        }
+    }
+}
+
+impl<Bh: Debug, Id: Debug> std::error::Error for BlockStateManagerError<Bh, Id> {}
+
+
+
+/// Value found by [`QueryParents::get_value_along`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheLayerHit<V, Id> {
+    /// `None` if key has been deleted
+    pub value: Option<V>,
+    /// Snapshot, which has written the value
+    pub snapshot_id: Id,
+    /// 1 for the direct parent
+    pub depth: usize,
 }
 
+/// Ancestor in the lineage of [`BlockStateManager`], resolved to the single map, which holds its values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LineageStep<Bh> {
+    /// Snapshot of the block
+    Snapshot(Bh),
+    /// Compacted chain entered from its newest block, together with number of blocks in it
+    Layer(Bh, usize),
+}
 
+/// Values are looked up in snapshots only, same as [`Snapshot::get_value`]:
+/// `None` means that storage should be checked, `Some(None)` means that key has been deleted.
 pub trait QueryParents {
     type Snapshot: Snapshot;
+    /// Value as it was before given snapshot: starting from its parent.
+    /// This is what block being executed should see.
+    #[allow(clippy::type_complexity)]
     fn get_value_recursively(&self,
-                             snapshot_block_hash: &SnapshotId,
+                             snapshot_id: &<Self::Snapshot as Snapshot>::Id,
                              key: &<Self::Snapshot as Snapshot>::Key,
-    ) -> Option<<Self::Snapshot as Snapshot>::Value>;
+    ) -> Option<Option<<Self::Snapshot as Snapshot>::Value>>;
+
+    /// Value as it is after given snapshot: starting from the snapshot itself, if it has been added.
+    #[allow(clippy::type_complexity)]
+    fn get_value_inclusive(&self,
+                           snapshot_id: &<Self::Snapshot as Snapshot>::Id,
+                           key: &<Self::Snapshot as Snapshot>::Key,
+    ) -> Option<Option<<Self::Snapshot as Snapshot>::Value>>;
+
+    /// Resolved chain of parents, which can be reused between reads.
+    type Lineage;
+
+    /// Parents of given snapshot, same as [`Self::get_value_recursively`] would traverse.
+    fn lineage(&self, snapshot_id: &<Self::Snapshot as Snapshot>::Id) -> Self::Lineage;
+
+    /// Same as [`Self::get_value_recursively`], but over already resolved parents.
+    /// Nothing is recorded to metrics, so caller can account the read together with the storage fallback.
+    #[allow(clippy::type_complexity)]
+    fn get_value_along(&self,
+                       lineage: &Self::Lineage,
+                       key: &<Self::Snapshot as Snapshot>::Key,
+    ) -> Option<CacheLayerHit<<Self::Snapshot as Snapshot>::Value, <Self::Snapshot as Snapshot>::Id>>;
+
+    /// Changes each time snapshots are removed, so previously resolved lineage should not be used anymore.
+    fn generation(&self) -> u64;
 }
 
 // Separate IMPL block, so no `Into<Payload>` bound here
@@ -108,26 +482,164 @@ impl<P, S, Bh> QueryParents for BlockStateManager<P, S, Bh>
     where
         P: Storage,
         S: Snapshot,
+        S::Id: SnapshotIdentity<Bh>,
         Bh: Eq + Hash + Clone
 {
     type Snapshot = S;
 
-    fn get_value_recursively(&self, snapshot_id: &SnapshotId, key: &S::Key) -> Option<S::Value> {
-        let snapshot_block_hash = self.snapshot_id_to_block_hash.get(snapshot_id)?;
+    fn get_value_recursively(&self, snapshot_id: &S::Id, key: &S::Key) -> Option<Option<S::Value>> {
+        let snapshot_block_hash = self.block_hash_of(snapshot_id)?;
         let parent_block_hash = self.blocks_to_parent.get(snapshot_block_hash)?;
-        let mut parent_snapshot = self.snapshots.get(parent_block_hash);
-        while parent_snapshot.is_some() {
-            let snapshot = parent_snapshot.unwrap();
-            let value = snapshot.get_value(key);
-            if value.is_some() {
-                return value;
+        self.record_hit(self.get_value_from_block(parent_block_hash, key), 1)
+    }
+
+    fn get_value_inclusive(&self, snapshot_id: &S::Id, key: &S::Key) -> Option<Option<S::Value>> {
+        let snapshot_block_hash = self.block_hash_of(snapshot_id)?;
+        if self.snapshots.contains_key(snapshot_block_hash) {
+            self.record_hit(self.get_value_from_block(snapshot_block_hash, key), 0)
+        } else {
+            self.get_value_recursively(snapshot_id, key)
+        }
+    }
+
+    /// Compacted chains entered from their newest block are resolved to their layer,
+    /// so each step is read with a single lookup.
+    type Lineage = Vec<LineageStep<Bh>>;
+
+    fn lineage(&self, snapshot_id: &S::Id) -> Vec<LineageStep<Bh>> {
+        let mut lineage = Vec::new();
+        let Some(mut current_block_hash) = self.block_hash_of(snapshot_id) else {
+            return lineage;
+        };
+        while let Some(parent_block_hash) = self.blocks_to_parent.get(current_block_hash) {
+            if let Some(layer) = self.compacted_layers.get(parent_block_hash) {
+                lineage.push(LineageStep::Layer(parent_block_hash.clone(), layer.blocks.len()));
+                current_block_hash = layer.oldest_block();
+                continue;
+            }
+            if !self.snapshots.contains_key(parent_block_hash) {
+                break;
+            }
+            lineage.push(LineageStep::Snapshot(parent_block_hash.clone()));
+            current_block_hash = parent_block_hash;
+        }
+        lineage
+    }
+
+    fn get_value_along(&self, lineage: &Vec<LineageStep<Bh>>, key: &S::Key) -> Option<CacheLayerHit<S::Value, S::Id>> {
+        let mut depth = 1;
+        for step in lineage {
+            match step {
+                LineageStep::Layer(newest_block_hash, blocks) => {
+                    let Some(layer) = self.compacted_layers.get(newest_block_hash) else {
+                        continue;
+                    };
+                    if let Some(compacted) = layer.values.get(key) {
+                        return Some(CacheLayerHit {
+                            value: compacted.value.clone(),
+                            snapshot_id: compacted.written_by.clone(),
+                            depth: depth + compacted.offset,
+                        });
+                    }
+                    depth += blocks;
+                }
+                LineageStep::Snapshot(block_hash) => {
+                    let Some(snapshot) = self.snapshots.get(block_hash) else {
+                        continue;
+                    };
+                    if snapshot.may_contain(key) {
+                        if let Some(value) = snapshot.get_value(key) {
+                            return Some(CacheLayerHit { value, snapshot_id: snapshot.get_id(), depth });
+                        }
+                    }
+                    depth += 1;
+                }
             }
-            let current_block_hash = self.snapshot_id_to_block_hash.get(&snapshot.get_id())?;
-            let parent_block_hash = self.blocks_to_parent.get(current_block_hash)?;
-            parent_snapshot = self.snapshots.get(parent_block_hash);
         }
         None
     }
+
+    fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+impl<P, S, Bh> BlockStateManager<P, S, Bh>
+    where
+        P: Storage,
+        S: Snapshot,
+        Bh: Eq + Hash + Clone
+{
+    /// Walks snapshots from given block back, until value is found or there's no more snapshots.
+    /// Compacted chains entered from the newest block are checked with a single lookup.
+    /// Value is returned with the number of blocks walked past, 0 if given block has it.
+    fn get_value_from_block(&self, block_hash: &Bh, key: &S::Key) -> Option<(Option<S::Value>, usize)> {
+        let mut current_block_hash = block_hash;
+        let mut depth = 0;
+        loop {
+            if let Some(layer) = self.compacted_layers.get(current_block_hash) {
+                if let Some(compacted) = layer.values.get(key) {
+                    return Some((compacted.value.clone(), depth + compacted.offset));
+                }
+                depth += layer.blocks.len();
+                current_block_hash = self.blocks_to_parent.get(layer.oldest_block())?;
+                continue;
+            }
+            let snapshot = self.snapshots.get(current_block_hash)?;
+            if snapshot.may_contain(key) {
+                if let Some(value) = snapshot.get_value(key) {
+                    return Some((value, depth));
+                }
+            }
+            depth += 1;
+            current_block_hash = self.blocks_to_parent.get(current_block_hash)?;
+        }
+    }
+
+    /// Pending block, which snapshot id has been issued for.
+    fn block_hash_of<'a>(&'a self, snapshot_id: &'a S::Id) -> Option<&'a Bh>
+        where
+            S::Id: SnapshotIdentity<Bh>,
+    {
+        match snapshot_id.block_hash() {
+            Some(block_hash) => self.registrations.contains_key(block_hash).then_some(block_hash),
+            None => self.snapshot_id_to_block_hash.get(snapshot_id),
+        }
+    }
+
+    fn register_snapshot_id(&mut self, block_hash: &Bh, snapshot_id: S::Id)
+        where
+            S::Id: SnapshotIdentity<Bh>,
+    {
+        if snapshot_id.block_hash().is_none() {
+            self.snapshot_id_to_block_hash.insert(snapshot_id.clone(), block_hash.clone());
+        }
+        self.registrations.insert(block_hash.clone(), Registration { snapshot_id, branch_token: Arc::new(()) });
+    }
+
+    fn unregister(&mut self, block_hash: &Bh) {
+        if let Some(registration) = self.registrations.remove(block_hash) {
+            self.snapshot_id_to_block_hash.remove(&registration.snapshot_id);
+        }
+    }
+
+    /// Value after given block, from pending snapshots only, together with the depth it has been found at.
+    /// Block, which is still being executed, is read as its parent.
+    fn find_pending_value(&self, block_hash: &Bh, key: &S::Key) -> Option<(Option<S::Value>, usize)> {
+        if self.snapshots.contains_key(block_hash) {
+            return self.get_value_from_block(block_hash, key);
+        }
+        let parent_block_hash = self.blocks_to_parent.get(block_hash)?;
+        let (value, depth) = self.get_value_from_block(parent_block_hash, key)?;
+        Some((value, depth + 1))
+    }
+
+    /// Lookup has started `offset` blocks away from the block, which is being read.
+    fn record_hit(&self, found: Option<(Option<S::Value>, usize)>, offset: usize) -> Option<Option<S::Value>> {
+        let (value, depth) = found?;
+        self.metrics.cache_layer_hit(depth + offset);
+        Some(value)
+    }
 }
 
 
@@ -137,26 +649,30 @@ impl<P, S, Bh> BlockStateManager<P, S, Bh>
         S: Snapshot + Into<P::Payload>,
         Bh: Eq + Hash + Clone
 {
-    pub fn new_locked(db: Arc<Mutex<P>>) -> Arc<RwLock<Self>> {
-        let block_state_manager = Arc::new(RwLock::new(Self {
+    pub fn new(db: Arc<Mutex<P>>) -> Self {
+        Self {
             db,
             chain_forks: Default::default(),
             blocks_to_parent: Default::default(),
             snapshots: Default::default(),
-            self_ref: None,
             snapshot_id_to_block_hash: Default::default(),
-            latest_snapshot_id: Default::default(),
-        }));
-        let self_ref = block_state_manager.clone();
-        {
-            let mut bm = block_state_manager.write().unwrap();
-            bm.self_ref = Some(self_ref);
+            registrations: Default::default(),
+            identity: Arc::new(()),
+            latest_snapshot_id: None,
+            last_finalized_block: None,
+            finalized_window: VecDeque::new(),
+            finalized_blocks: HashSet::new(),
+            generation: 0,
+            compacted_layers: Default::default(),
+            compacted_blocks: Default::default(),
+            limits: PendingLimits::default(),
+            pending_bytes: 0,
+            journal: None,
+            journal_failure: None,
+            journal_records: 0,
+            metrics: Arc::new(NoopMetrics),
+            subscribers: Vec::new(),
         }
-        block_state_manager
-    }
-
-    pub fn stop(mut self) {
-        self.self_ref = None
     }
 
     pub fn is_empty(&self) -> bool {
@@ -164,196 +680,2422 @@ impl<P, S, Bh> BlockStateManager<P, S, Bh>
             && self.blocks_to_parent.is_empty()
             && self.snapshots.is_empty()
             && self.snapshot_id_to_block_hash.is_empty()
+            && self.registrations.is_empty()
     }
 
-    pub fn get_new_ref(&mut self, prev_block_hash: &Bh, current_block_hash: &Bh) -> TreeQuery<P, BlockStateManager<P, S, Bh>> {
-        self.latest_snapshot_id += 1;
-        let new_snapshot_ref = TreeQuery {
-            id: self.latest_snapshot_id,
-            db: self.db.clone(),
-            manager: ReadOnlyLock::new(self.self_ref.clone().unwrap().clone()),
-        };
+    pub fn set_limits(&mut self, limits: PendingLimits) {
+        self.limits = limits;
+    }
 
-        self.snapshot_id_to_block_hash.insert(self.latest_snapshot_id, current_block_hash.clone());
+    pub fn set_metrics(&mut self, metrics: Arc<dyn Metrics>) {
+        self.metrics = metrics;
+    }
 
-        let c = self.blocks_to_parent.insert(current_block_hash.clone(), prev_block_hash.clone());
-        // TODO: Maybe assert that parent is the same? Then
-        assert!(c.is_none(), "current block hash has already snapshot requested");
-        self.chain_forks.entry(prev_block_hash.clone()).or_insert(Vec::new()).push(current_block_hash.clone());
+    pub fn metrics(&self) -> Arc<dyn Metrics> {
+        self.metrics.clone()
+    }
 
-        new_snapshot_ref
+    /// Receives every event after subscription, until receiver is dropped.
+    pub fn subscribe(&mut self) -> Receiver<ForkTreeEvent<Bh, S::Id, S::Key>> {
+        let (sender, receiver) = channel();
+        self.subscribers.push(sender);
+        receiver
     }
 
-    pub fn add_snapshot(&mut self, snapshot: S) {
-        let snapshot_block_hash = self.snapshot_id_to_block_hash.get(&snapshot.get_id()).unwrap();
-        self.snapshots.insert(snapshot_block_hash.clone(), snapshot);
+    fn emit(&mut self, event: ForkTreeEvent<Bh, S::Id, S::Key>) {
+        self.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
     }
 
-    fn remove_snapshot(&mut self, block_hash: &Bh) -> S {
-        let snapshot = self.snapshots.remove(&block_hash).expect("Tried to remove non-existing snapshot: self.snapshots");
-        let remove_block_hash = self.snapshot_id_to_block_hash.remove(&snapshot.get_id()).unwrap();
-        // assert_eq!(&remove_block_hash, block_hash, "something");
-        snapshot
+    pub fn usage(&self) -> PendingUsage {
+        PendingUsage {
+            blocks: self.blocks_to_parent.len(),
+            bytes: self.pending_bytes,
+        }
     }
 
-    pub fn finalize_snapshot(&mut self, block_hash: &Bh) {
-        let snapshot = self.remove_snapshot(block_hash);
-        let payload = snapshot.into();
-        {
-            let mut db = self.db.lock().unwrap();
-            db.commit(payload);
+    /// All blocks, which have not been finalized or discarded yet, in breadth-first order from the roots.
+    /// Includes blocks which snapshot has not been added yet.
+    pub fn pending_blocks(&self) -> Vec<Bh> {
+        let mut pending = Vec::with_capacity(self.blocks_to_parent.len());
+        let mut to_visit: VecDeque<&Bh> = self.roots().collect();
+        while let Some(block_hash) = to_visit.pop_front() {
+            for child in self.children_of(block_hash) {
+                pending.push(child.clone());
+                to_visit.push_back(child);
+            }
         }
+        pending
+    }
 
+    pub fn children_of(&self, block_hash: &Bh) -> &[Bh] {
+        self.chain_forks.get(block_hash).map(|children| children.as_slice()).unwrap_or_default()
+    }
 
-        if let Some(parent_block_hash) = self.blocks_to_parent.remove(block_hash) {
-            let mut to_discard: Vec<_> = self.chain_forks.remove(&parent_block_hash).expect("Inconsistent chain_forks")
-                .into_iter()
-                .filter(|bh| bh != block_hash)
-                .collect();
-            while !to_discard.is_empty() {
-                let next_to_discard = to_discard.pop().unwrap();
-                let next_children_to_discard = self.chain_forks.remove(&next_to_discard).unwrap_or(Default::default());
-                to_discard.extend(next_children_to_discard);
+    pub fn parent_of(&self, block_hash: &Bh) -> Option<&Bh> {
+        self.blocks_to_parent.get(block_hash)
+    }
 
-                self.blocks_to_parent.remove(&next_to_discard).unwrap();
-                self.remove_snapshot(&next_to_discard);
-            }
+    /// Parents of the given block, from the closest one, back to the root, which is already in the storage.
+    pub fn ancestors_of(&self, block_hash: &Bh) -> Vec<Bh> {
+        let mut ancestors = Vec::new();
+        let mut current_block_hash = block_hash;
+        while let Some(parent_block_hash) = self.blocks_to_parent.get(current_block_hash) {
+            ancestors.push(parent_block_hash.clone());
+            current_block_hash = parent_block_hash;
         }
+        ancestors
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::BlockHash;
-    use crate::db::Database;
-    use crate::state::{DB, StateCheckpoint};
-    use super::*;
+    /// Pending blocks without children.
+    pub fn tips(&self) -> Vec<Bh> {
+        self.pending_blocks()
+            .into_iter()
+            .filter(|bh| !self.chain_forks.contains_key(bh))
+            .collect()
+    }
 
-    fn write_values(db: DB, snapshot_ref: TreeQuery<Database, BlockStateManager<Database, FrozenSnapshot, BlockHash>>, values: &[(&str, &str)]) -> FrozenSnapshot {
-        let checkpoint = StateCheckpoint::new(snapshot_ref);
-        let mut working_set = checkpoint.into_revertable();
-        for (key, value) in values {
-            let key = Key::from(key.to_string());
-            let value = Value::from(value.to_string());
-            working_set.set(&key, value);
-        }
-        let checkpoint = working_set.commit();
-        let (_witness, snapshot) = checkpoint.freeze();
-        snapshot
+    /// Number of pending blocks in the longest branch.
+    pub fn depth(&self) -> usize {
+        self.tips()
+            .iter()
+            .map(|tip| self.ancestors_of(tip).len())
+            .max()
+            .unwrap_or_default()
     }
 
-    mod fork_tree_manager {
-        use super::*;
+    pub fn has_snapshot(&self, block_hash: &Bh) -> bool {
+        self.snapshots.contains_key(block_hash)
+    }
 
-        #[test]
-        fn new() {
-            let db = DB::default();
-            let state_manager = BlockStateManager::<Database, FrozenSnapshot, BlockHash>::new_locked(db.clone());
-            let state_manager = state_manager.write().unwrap();
-            assert!(state_manager.self_ref.is_some());
-            {
-                let db = db.lock().unwrap();
-                assert!(db.data.is_empty());
+    /// The closest block, which both given blocks descend from, or are equal to.
+    /// Can be the root, which is already in the storage.
+    pub fn common_ancestor(&self, block_hash_a: &Bh, block_hash_b: &Bh) -> Option<Bh> {
+        let lineage = |block_hash: &Bh| -> Option<Vec<Bh>> {
+            if !self.blocks_to_parent.contains_key(block_hash) && !self.is_root(block_hash) {
+                return None;
+            }
+            let mut lineage = vec![block_hash.clone()];
+            lineage.extend(self.ancestors_of(block_hash));
+            Some(lineage)
+        };
+        let lineage_a = lineage(block_hash_a)?;
+        let lineage_b = lineage(block_hash_b)?;
+        lineage_a.into_iter().find(|bh| lineage_b.contains(bh))
+    }
+
+    /// Blocks from the given one (inclusive) up to the ancestor (exclusive).
+    fn branch_until(&self, block_hash: &Bh, ancestor: &Bh) -> Vec<Bh> {
+        let mut branch = Vec::new();
+        let mut current_block_hash = block_hash;
+        while current_block_hash != ancestor {
+            branch.push(current_block_hash.clone());
+            match self.blocks_to_parent.get(current_block_hash) {
+                Some(parent_block_hash) => current_block_hash = parent_block_hash,
+                None => break,
             }
-            assert!(state_manager.is_empty());
         }
+        branch
+    }
 
+    fn snapshot_id_of(&self, block_hash: &Bh) -> Option<S::Id> {
+        self.registrations.get(block_hash).map(|registration| registration.snapshot_id.clone())
+    }
 
-        #[test]
-        fn linear_progression_with_2_blocks_delay() {
-            let db = DB::default();
-            let state_manager = BlockStateManager::new_locked(db.clone());
-            let mut state_manager = state_manager.write().unwrap();
-            assert!(state_manager.is_empty());
-            let genesis_block = "genesis".to_string();
-            let block_a = "a".to_string();
-            let block_b = "b".to_string();
-            let block_c = "c".to_string();
+    /// Finalized blocks that still have pending children.
+    fn roots(&self) -> impl Iterator<Item=&Bh> {
+        self.chain_forks.keys().filter(|bh| self.is_root(bh))
+    }
 
-            // Block A
-            let block_a_values = vec![
-                ("x", "1"),
-                ("y", "2"),
-            ];
-            let snapshot_ref = state_manager.get_new_ref(&genesis_block, &block_a);
+    /// Block is a root, if it has children, but no parent. It means it has been already finalized.
+    fn is_root(&self, block_hash: &Bh) -> bool {
+        self.chain_forks.contains_key(block_hash) && !self.blocks_to_parent.contains_key(block_hash)
+    }
 
-            assert!(!state_manager.is_empty());
-            let snapshot = write_values(db.clone(), snapshot_ref, &block_a_values);
-            state_manager.add_snapshot(snapshot);
-            assert!(!state_manager.is_empty());
-            {
-                assert!(db.lock().unwrap().data.is_empty());
-            }
+    /// Adds new block to the fork tree and returns id, that its snapshot is going to have.
+    /// [`BlockStateManagerHandle::get_new_ref`] wraps it into [`TreeQuery`].
+    pub fn register_block(&mut self, prev_block_hash: &Bh, current_block_hash: &Bh) -> Result<S::Id, BlockStateManagerError<Bh, S::Id>>
+        where
+            S::Id: SnapshotIdentity<Bh>,
+    {
+        if self.blocks_to_parent.contains_key(current_block_hash)
+            || self.is_root(current_block_hash)
+            || self.is_finalized(current_block_hash) {
+            return Err(BlockStateManagerError::DuplicateBlock(current_block_hash.clone()));
+        }
+        // Block cannot descend from itself, otherwise walking its ancestors never ends
+        if prev_block_hash == current_block_hash {
+            return Err(BlockStateManagerError::UnknownParent(prev_block_hash.clone()));
+        }
+        // Before anything is finalized, empty manager accepts any parent, as it is considered to be already in the storage.
+        // After that, only the last finalized block is.
+        let parent_is_known = (self.is_empty() && self.last_finalized_block.is_none())
+            || self.snapshots.contains_key(prev_block_hash)
+            || self.is_root(prev_block_hash)
+            || self.last_finalized_block.as_ref() == Some(prev_block_hash);
+        if !parent_is_known {
+            return Err(BlockStateManagerError::UnknownParent(prev_block_hash.clone()));
+        }
+        let usage = self.usage();
+        if self.limits.is_exceeded_by(&usage) {
+            return Err(BlockStateManagerError::BackPressure(usage));
+        }
 
-            // Block B
-            let block_b_values = vec![
-                ("x", "3"),
-                ("z", "4"),
-            ];
-            let snapshot_ref = state_manager.get_new_ref(&block_a, &block_b);
-            let snapshot = write_values(db.clone(), snapshot_ref, &block_b_values);
-            let snapshot_id_b = snapshot.get_id().clone();
-            state_manager.add_snapshot(snapshot);
-            assert_eq!(Some(CacheValue::from(Value::from("1".to_string()))), state_manager.get_value_recursively(&snapshot_id_b, &CacheKey::from(Key::from("x".to_string()))));
-            {
-                assert!(db.lock().unwrap().data.is_empty());
-            }
-            println!("AFTER B: {:?}", state_manager);
-            // Finalizing A
-            state_manager.finalize_snapshot(&block_a);
-            {
-                let db = db.lock().unwrap();
-                assert!(!db.data.is_empty());
-                assert_eq!(Some("1".to_string()), db.get("x"));
-                assert_eq!(Some("2".to_string()), db.get("y"));
-                assert_eq!(None, db.get("z"));
-            }
-            println!("AFTER FINALIZING A: {:?}", state_manager);
+        let snapshot_id = S::Id::next_id(self.latest_snapshot_id.as_ref(), current_block_hash);
+        self.latest_snapshot_id = Some(snapshot_id.clone());
+        self.register_snapshot_id(current_block_hash, snapshot_id.clone());
+        self.blocks_to_parent.insert(current_block_hash.clone(), prev_block_hash.clone());
+        self.chain_forks.entry(prev_block_hash.clone()).or_default().push(current_block_hash.clone());
+        self.metrics.fork_created();
 
-            // Block C
-            let block_c_values = vec![
-                ("x", "5"),
-                ("z", "6"),
-            ];
-            let snapshot_ref = state_manager.get_new_ref(&block_b, &block_c);
-            let snapshot = write_values(db.clone(), snapshot_ref, &block_c_values);
-            state_manager.add_snapshot(snapshot);
-            println!("AFTER C: {:?}", state_manager);
-            // Finalizing B
-            state_manager.finalize_snapshot(&block_b);
-            assert!(!state_manager.is_empty());
-            {
-                let db = db.lock().unwrap();
-                assert!(!db.data.is_empty());
-                assert_eq!(Some("3".to_string()), db.get("x"));
-                assert_eq!(Some("2".to_string()), db.get("y"));
-                assert_eq!(Some("4".to_string()), db.get("z"));
-            }
+        Ok(snapshot_id)
+    }
 
-            state_manager.finalize_snapshot(&block_c);
-            // TODO: Finalize everything, it should be clean
-            println!("AFTER FINALIZING C: {:?}", state_manager);
-            assert!(state_manager.is_empty());
+    /// Reference, that is alive only while block is pending.
+    pub fn branch_token(&self, block_hash: &Bh) -> BranchToken {
+        BranchToken {
+            manager: Arc::downgrade(&self.identity),
+            registration: self.registrations.get(block_hash)
+                .map(|registration| Arc::downgrade(&registration.branch_token))
+                .unwrap_or_default(),
         }
+    }
 
-        #[test]
-        #[ignore = "TBD"]
-        fn fork_added() {}
-
-        #[test]
-        #[ignore = "TBD"]
-        fn adding_alien_snapshot() {}
+    pub fn add_snapshot(&mut self, snapshot: S) -> Result<(), BlockStateManagerError<Bh, S::Id>>
+        where
+            S::Id: SnapshotIdentity<Bh>,
+    {
+        let snapshot_id = snapshot.get_id();
+        let branch_token = snapshot.branch_token();
+        // Checked first, as token of a dropped manager is dead as well
+        if !Weak::ptr_eq(&branch_token.manager, &Arc::downgrade(&self.identity)) {
+            return Err(BlockStateManagerError::AlienSnapshot(snapshot_id));
+        }
+        // Only manager holds the token, so it is dead once block is pruned
+        if branch_token.is_dead() {
+            return Err(BlockStateManagerError::CancelledSnapshot(snapshot_id));
+        }
+        let block_hash = self.block_hash_of(&snapshot_id)
+            .filter(|bh| Weak::ptr_eq(&branch_token.registration, &Arc::downgrade(&self.registrations[*bh].branch_token)))
+            .ok_or_else(|| BlockStateManagerError::AlienSnapshot(snapshot_id.clone()))?
+            .clone();
+        if self.snapshots.contains_key(&block_hash) {
+            return Err(BlockStateManagerError::DuplicateBlock(block_hash));
+        }
+        self.pending_bytes += snapshot.size_in_bytes();
+        self.snapshots.insert(block_hash.clone(), snapshot);
+        self.journal_snapshot_added(&block_hash);
+        self.metrics.pending_snapshots(self.snapshots.len());
+        self.emit(ForkTreeEvent::SnapshotAdded { block: block_hash, id: snapshot_id });
+        Ok(())
+    }
 
-        #[test]
-        #[ignore = "TBD"]
-        fn finalizing_alien_block() {}
+    fn remove_snapshot(&mut self, block_hash: &Bh) -> Result<S, BlockStateManagerError<Bh, S::Id>> {
+        let snapshot = self.snapshots.remove(block_hash)
+            .ok_or_else(|| BlockStateManagerError::MissingSnapshot(block_hash.clone()))?;
+        self.pending_bytes -= snapshot.size_in_bytes();
+        self.unregister(block_hash);
+        self.generation += 1;
+        self.uncompact_finalized(block_hash, &snapshot);
+        Ok(snapshot)
+    }
 
-        #[test]
-        #[ignore = "TBD"]
-        fn finalizing_same_block_hash_twice() {}
+    /// Removes all traces of the block, regardless if its snapshot has been added or not.
+    fn forget_block(&mut self, block_hash: &Bh) {
+        self.generation += 1;
+        self.blocks_to_parent.remove(block_hash);
+        if let Some(snapshot) = self.snapshots.remove(block_hash) {
+            self.pending_bytes -= snapshot.size_in_bytes();
+        }
+        // Snapshot, which is still being executed, is going to be rejected when it arrives
+        self.unregister(block_hash);
+        if let Some(newest_block_hash) = self.compacted_blocks.remove(block_hash) {
+            self.drop_compacted_layer(&newest_block_hash);
+        }
+    }
 
-        #[test]
-        #[ignore = "TBD"]
-        fn requesting_ref_from_same_block_twice() {}
+    /// Finalized block is always the oldest in its compacted layer, so rest of the layer stays valid.
+    /// Its values are now the same as in the storage, and are left for the storage to serve.
+    fn uncompact_finalized(&mut self, block_hash: &Bh, snapshot: &S) {
+        let Some(newest_block_hash) = self.compacted_blocks.remove(block_hash) else {
+            return;
+        };
+        let Some(layer) = self.compacted_layers.get_mut(&newest_block_hash) else {
+            return;
+        };
+        let snapshot_id = snapshot.get_id();
+        let values_before = layer.values.len();
+        layer.blocks.retain(|bh| bh != block_hash);
+        layer.values.retain(|_, compacted| compacted.written_by != snapshot_id);
+        let copied_bytes = copied_bytes(snapshot, values_before - layer.values.len());
+        layer.bytes -= copied_bytes;
+        self.pending_bytes -= copied_bytes;
+        if layer.blocks.len() < 2 {
+            self.drop_compacted_layer(&newest_block_hash);
+        }
     }
-}
\ No newline at end of file
+
+    fn drop_compacted_layer(&mut self, newest_block_hash: &Bh) {
+        if let Some(layer) = self.compacted_layers.remove(newest_block_hash) {
+            self.pending_bytes -= layer.bytes;
+            for block_hash in layer.blocks {
+                self.compacted_blocks.remove(&block_hash);
+            }
+        }
+    }
+
+    /// Commits snapshot of the given block to the storage and discards all competing forks.
+    /// Block must be the oldest pending one: finalizing it before its parent would put
+    /// parent's stale values on top of the committed ones. Use [`Self::finalize_up_to`] to commit whole chain.
+    pub fn finalize_snapshot(&mut self, block_hash: &Bh) -> Result<(), BlockStateManagerError<Bh, S::Id>> {
+        if self.is_finalized(block_hash) || self.is_root(block_hash) {
+            return Err(BlockStateManagerError::AlreadyFinalized(block_hash.clone()));
+        }
+        let parent_block_hash = self.blocks_to_parent.get(block_hash)
+            .ok_or_else(|| BlockStateManagerError::UnknownBlock(block_hash.clone()))?;
+        if self.blocks_to_parent.contains_key(parent_block_hash) {
+            return Err(BlockStateManagerError::NonRootFinalization(block_hash.clone()));
+        }
+
+        let snapshot = self.remove_snapshot(block_hash)?;
+        let written_keys = snapshot.written_keys();
+        let payload = snapshot.into();
+        self.db.lock().unwrap().commit(payload);
+
+        self.emit(ForkTreeEvent::Finalized { block: block_hash.clone(), written_keys });
+        self.detach_finalized(block_hash);
+        self.metrics.blocks_finalized(1);
+        self.metrics.pending_snapshots(self.snapshots.len());
+        self.journal_blocks_finalized(std::slice::from_ref(block_hash));
+        Ok(())
+    }
+
+    /// Finalizes given block together with all its pending ancestors, starting from the oldest one.
+    /// Nothing is written if any of the snapshots in the chain is missing.
+    /// Storage is locked for the whole chain, so readers never observe partially committed chain.
+    /// Returns finalized block hashes in order they have been committed.
+    pub fn finalize_up_to(&mut self, block_hash: &Bh) -> Result<Vec<Bh>, BlockStateManagerError<Bh, S::Id>> {
+        if self.is_finalized(block_hash) || self.is_root(block_hash) {
+            return Err(BlockStateManagerError::AlreadyFinalized(block_hash.clone()));
+        }
+        let mut parent_block_hash = self.blocks_to_parent.get(block_hash)
+            .ok_or_else(|| BlockStateManagerError::UnknownBlock(block_hash.clone()))?;
+        let mut chain = vec![block_hash.clone()];
+        while self.blocks_to_parent.contains_key(parent_block_hash) {
+            chain.push(parent_block_hash.clone());
+            parent_block_hash = &self.blocks_to_parent[parent_block_hash];
+        }
+        chain.reverse();
+        if let Some(missing) = chain.iter().find(|bh| !self.snapshots.contains_key(*bh)) {
+            return Err(BlockStateManagerError::MissingSnapshot(missing.clone()));
+        }
+
+        let mut payloads = Vec::with_capacity(chain.len());
+        let mut written_keys = Vec::with_capacity(chain.len());
+        for finalized_block_hash in &chain {
+            let snapshot = self.remove_snapshot(finalized_block_hash)?;
+            written_keys.push(snapshot.written_keys());
+            payloads.push(snapshot.into());
+        }
+        {
+            let mut db = self.db.lock().unwrap();
+            for payload in payloads {
+                db.commit(payload);
+            }
+        }
+
+        for (finalized_block_hash, written_keys) in chain.iter().zip(written_keys) {
+            self.emit(ForkTreeEvent::Finalized { block: finalized_block_hash.clone(), written_keys });
+            self.detach_finalized(finalized_block_hash);
+        }
+        self.metrics.blocks_finalized(chain.len());
+        self.metrics.pending_snapshots(self.snapshots.len());
+        self.journal_blocks_finalized(&chain);
+        Ok(chain)
+    }
+
+    /// Unlinks just committed block from its parent and discards all competing forks.
+    fn detach_finalized(&mut self, block_hash: &Bh) {
+        if let Some(parent_block_hash) = self.blocks_to_parent.remove(block_hash) {
+            let siblings = self.chain_forks.remove(&parent_block_hash).unwrap_or_default();
+            for sibling in siblings.iter().filter(|bh| *bh != block_hash) {
+                self.discard_subtree(sibling);
+            }
+        }
+        self.record_finalized(block_hash);
+    }
+
+    fn record_finalized(&mut self, block_hash: &Bh) {
+        self.last_finalized_block = Some(block_hash.clone());
+        if self.finalized_blocks.insert(block_hash.clone()) {
+            self.finalized_window.push_back(block_hash.clone());
+        }
+        if self.finalized_window.len() > FINALIZED_BLOCKS_KEPT {
+            if let Some(forgotten) = self.finalized_window.pop_front() {
+                self.finalized_blocks.remove(&forgotten);
+            }
+        }
+    }
+
+    /// Block is one of the last [`FINALIZED_BLOCKS_KEPT`] committed blocks.
+    pub fn is_finalized(&self, block_hash: &Bh) -> bool {
+        self.finalized_blocks.contains(block_hash)
+    }
+
+    /// Removes block and all its descendants, when block has been reorged out on DA layer.
+    /// Returns hashes of all discarded blocks, starting from the given one.
+    pub fn discard_branch(&mut self, block_hash: &Bh) -> Result<Vec<Bh>, BlockStateManagerError<Bh, S::Id>> {
+        if self.is_finalized(block_hash) || self.is_root(block_hash) {
+            return Err(BlockStateManagerError::AlreadyFinalized(block_hash.clone()));
+        }
+        let parent_block_hash = self.blocks_to_parent.get(block_hash)
+            .ok_or_else(|| BlockStateManagerError::UnknownBlock(block_hash.clone()))?
+            .clone();
+
+        if let Some(siblings) = self.chain_forks.get_mut(&parent_block_hash) {
+            siblings.retain(|bh| bh != block_hash);
+            if siblings.is_empty() {
+                self.chain_forks.remove(&parent_block_hash);
+            }
+        }
+
+        let discarded = self.discard_subtree(block_hash);
+        self.metrics.pending_snapshots(self.snapshots.len());
+        self.journal_branch_discarded(block_hash);
+        Ok(discarded)
+    }
+
+    /// Journal write, which has failed and has not been repaired yet, see [`Self::repair_journal`].
+    /// Changes are applied regardless, but journal is not written until it is repaired,
+    /// so blocks added since then need to be executed again, if node restarts before that.
+    pub fn journal_failure(&self) -> Option<std::io::ErrorKind> {
+        self.journal_failure
+    }
+
+    /// Replaces journal with the current pending tree, so it is written again after [`Self::journal_failure`].
+    pub fn repair_journal(&mut self) -> std::io::Result<()> {
+        let result = self.try_rewrite_journal();
+        self.journal_failure = result.as_ref().err().map(|e| e.kind());
+        result
+    }
+
+    /// Journal is left as is after failure, as records appended after the missing one cannot be replayed.
+    /// Failure does not undo the change, which is already done in memory, it is kept in [`Self::journal_failure`] instead.
+    fn journal_snapshot_added(&mut self, block_hash: &Bh) {
+        if self.journal_failure.is_some() {
+            return;
+        }
+        if let Some(journal) = self.journal.as_mut() {
+            let parent_block_hash = &self.blocks_to_parent[block_hash];
+            let snapshot = &self.snapshots[block_hash];
+            match journal.snapshot_added(parent_block_hash, block_hash, snapshot, self.latest_snapshot_id.as_ref()) {
+                Ok(()) => self.journal_records += 1,
+                Err(e) => self.journal_failure = Some(e.kind()),
+            }
+        }
+    }
+
+    fn journal_blocks_finalized(&mut self, block_hashes: &[Bh]) {
+        if self.journal_failure.is_some() {
+            return;
+        }
+        if let Some(journal) = self.journal.as_mut() {
+            match journal.blocks_finalized(block_hashes, self.latest_snapshot_id.as_ref()) {
+                Ok(()) => self.journal_records += block_hashes.len(),
+                Err(e) => self.journal_failure = Some(e.kind()),
+            }
+        }
+        self.compact_journal();
+    }
+
+    fn journal_branch_discarded(&mut self, block_hash: &Bh) {
+        if self.journal_failure.is_some() {
+            return;
+        }
+        if let Some(journal) = self.journal.as_mut() {
+            match journal.branch_discarded(block_hash, self.latest_snapshot_id.as_ref()) {
+                Ok(()) => self.journal_records += 1,
+                Err(e) => self.journal_failure = Some(e.kind()),
+            }
+        }
+        self.compact_journal();
+    }
+
+    fn compact_journal(&mut self) {
+        if self.journal_failure.is_none() && self.journal_records >= JOURNAL_REWRITE_RECORDS {
+            self.journal_failure = self.try_rewrite_journal().err().map(|e| e.kind());
+        }
+    }
+
+    fn try_rewrite_journal(&mut self) -> std::io::Result<()> {
+        if self.journal.is_none() {
+            return Ok(());
+        }
+        let pending_blocks = self.pending_blocks();
+        let blocks: Vec<_> = pending_blocks.iter()
+            .filter_map(|bh| Some((&self.blocks_to_parent[bh], bh, self.snapshots.get(bh)?)))
+            .collect();
+        if let Some(journal) = self.journal.as_mut() {
+            journal.rewrite(self.last_finalized_block.as_ref(), self.latest_snapshot_id.as_ref(), &blocks)?;
+        }
+        // Header and pending blocks
+        self.journal_records = 1 + blocks.len();
+        Ok(())
+    }
+
+    /// Forgets block and all its descendants, but does not touch its parent `chain_forks` entry.
+    fn discard_subtree(&mut self, block_hash: &Bh) -> Vec<Bh> {
+        let mut discarded = Vec::new();
+        let mut to_discard = VecDeque::from([block_hash.clone()]);
+        while let Some(next_to_discard) = to_discard.pop_front() {
+            let next_children_to_discard = self.chain_forks.remove(&next_to_discard).unwrap_or_default();
+            to_discard.extend(next_children_to_discard);
+            self.forget_block(&next_to_discard);
+            discarded.push(next_to_discard);
+        }
+        self.metrics.blocks_discarded(discarded.len());
+        self.emit(ForkTreeEvent::Discarded { blocks: discarded.clone() });
+        discarded
+    }
+}
+
+// Persistence of the pending tree
+impl<P, S, Bh> BlockStateManager<P, S, Bh>
+    where
+        P: Storage,
+        S: Snapshot + Into<P::Payload> + JournalCodec,
+        S::Id: JournalCodec,
+        Bh: Eq + Hash + Clone + JournalCodec
+{
+    /// Rebuilds pending tree from the journal at given path, and keeps writing every change to it.
+    /// Blocks, which snapshots have not been added before restart, are not in the journal and need to be executed again.
+    /// Storage should already contain everything finalized before restart.
+    pub fn recover(path: impl AsRef<Path>, db: Arc<Mutex<P>>) -> std::io::Result<Self>
+        where
+            S::Id: SnapshotIdentity<Bh>,
+    {
+        let mut manager = Self::new(db);
+        for record in FileJournal::read::<Bh, S>(&path)? {
+            match record {
+                JournalRecord::Header { last_finalized_block, latest_snapshot_id } => {
+                    if let Some(last_finalized_block) = last_finalized_block {
+                        manager.record_finalized(&last_finalized_block);
+                    }
+                    manager.latest_snapshot_id = latest_snapshot_id;
+                }
+                JournalRecord::SnapshotAdded { parent_block_hash, block_hash, snapshot, latest_snapshot_id } => {
+                    manager.register_snapshot_id(&block_hash, snapshot.get_id());
+                    manager.chain_forks.entry(parent_block_hash.clone()).or_default().push(block_hash.clone());
+                    manager.blocks_to_parent.insert(block_hash.clone(), parent_block_hash);
+                    manager.pending_bytes += snapshot.size_in_bytes();
+                    manager.snapshots.insert(block_hash, snapshot);
+                    manager.latest_snapshot_id = latest_snapshot_id;
+                }
+                JournalRecord::Finalized { block_hash, latest_snapshot_id } => {
+                    // Storage already has it, so it is only removed from the tree
+                    manager.remove_snapshot(&block_hash)
+                        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "journal finalizes block, which is not pending"))?;
+                    manager.detach_finalized(&block_hash);
+                    manager.latest_snapshot_id = latest_snapshot_id;
+                }
+                JournalRecord::Discarded { block_hash, latest_snapshot_id } => {
+                    manager.discard_branch(&block_hash)
+                        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "journal discards block, which is not pending"))?;
+                    manager.latest_snapshot_id = latest_snapshot_id;
+                }
+            }
+        }
+        manager.journal = Some(Box::new(FileJournal::open(&path)?));
+        // Drops record, which could have been torn by the crash, so new ones are not appended after it
+        manager.try_rewrite_journal()?;
+        Ok(manager)
+    }
+}
+
+// Queries, which resolve values through snapshots and the storage
+impl<P, S, Bh> BlockStateManager<P, S, Bh>
+    where
+        P: Storage<Key=S::Key, Value=S::Value>,
+        S: Snapshot + Into<P::Payload>,
+        S::Value: PartialEq,
+        Bh: Eq + Hash + Clone
+{
+    /// Keys, which have different value after one block and another.
+    /// Only snapshots after the common ancestor of two blocks are inspected,
+    /// as everything before is shared.
+    /// Returns `None` if blocks do not have common ancestor.
+    pub fn diff_between(&self, block_hash_a: &Bh, block_hash_b: &Bh) -> Option<Vec<S::Key>> {
+        let common_ancestor = self.common_ancestor(block_hash_a, block_hash_b)?;
+        let mut seen = HashSet::new();
+        let mut diff = Vec::new();
+        let side_a = self.branch_until(block_hash_a, &common_ancestor);
+        let side_b = self.branch_until(block_hash_b, &common_ancestor);
+        for block_hash in side_a.iter().chain(side_b.iter()) {
+            let Some(snapshot) = self.snapshots.get(block_hash) else {
+                continue;
+            };
+            for key in snapshot.written_keys() {
+                if !seen.insert(key.clone()) {
+                    continue;
+                }
+                if self.resolve_value(block_hash_a, &key) != self.resolve_value(block_hash_b, &key) {
+                    diff.push(key);
+                }
+            }
+        }
+        Some(diff)
+    }
+
+    /// Value of the key after given block has been applied.
+    /// Block can be pending or the latest finalized one, and fork tree is not modified.
+    pub fn get_at(&self, block_hash: &Bh, key: &S::Key) -> Result<Option<S::Value>, BlockStateManagerError<Bh, S::Id>> {
+        self.check_readable(block_hash)?;
+        if let Some((value, depth)) = self.find_pending_value(block_hash, key) {
+            self.metrics.cache_layer_hit(depth);
+            return Ok(value);
+        }
+        self.metrics.storage_fallback();
+        let db = self.db.lock().unwrap();
+        Ok(db.get(key))
+    }
+
+    fn check_readable(&self, block_hash: &Bh) -> Result<(), BlockStateManagerError<Bh, S::Id>> {
+        if self.blocks_to_parent.contains_key(block_hash) {
+            if !self.snapshots.contains_key(block_hash) {
+                return Err(BlockStateManagerError::MissingSnapshot(block_hash.clone()));
+            }
+            return Ok(());
+        }
+        if self.is_root(block_hash) || self.last_finalized_block.as_ref() == Some(block_hash) {
+            return Ok(());
+        }
+        Err(BlockStateManagerError::UnknownBlock(block_hash.clone()))
+    }
+
+    /// Own snapshot first, then parents and then the storage.
+    /// Used for traversals done by the manager itself, so nothing is recorded to metrics.
+    fn resolve_value(&self, block_hash: &Bh, key: &S::Key) -> Option<S::Value> {
+        if let Some((value, _)) = self.find_pending_value(block_hash, key) {
+            return value;
+        }
+        let db = self.db.lock().unwrap();
+        db.get(key)
+    }
+}
+
+/// Shared owner of [`BlockStateManager`], which hands out [`TreeQuery`] and [`StateView`] referencing it.
+/// Manager does not reference itself, so it is dropped together with the last handle and query.
+pub struct BlockStateManagerHandle<P: Storage, S: Snapshot, Bh> {
+    inner: Arc<RwLock<BlockStateManager<P, S, Bh>>>,
+}
+
+impl<P: Storage, S: Snapshot, Bh> Clone for BlockStateManagerHandle<P, S, Bh> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<P, S, Bh> BlockStateManagerHandle<P, S, Bh>
+    where
+        P: Storage,
+        S: Snapshot + Into<P::Payload>,
+        Bh: Eq + Hash + Clone
+{
+    pub fn new(db: Arc<Mutex<P>>) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(BlockStateManager::new(db))),
+        }
+    }
+
+    /// See [`BlockStateManager::recover`].
+    pub fn recover(path: impl AsRef<Path>, db: Arc<Mutex<P>>) -> std::io::Result<Self>
+        where
+            S: JournalCodec,
+            S::Id: JournalCodec + SnapshotIdentity<Bh>,
+            Bh: JournalCodec,
+    {
+        Ok(Self {
+            inner: Arc::new(RwLock::new(BlockStateManager::recover(path, db)?)),
+        })
+    }
+
+    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, BlockStateManager<P, S, Bh>>> {
+        self.inner.read()
+    }
+
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, BlockStateManager<P, S, Bh>>> {
+        self.inner.write()
+    }
+
+    pub fn read_only(&self) -> ReadOnlyLock<BlockStateManager<P, S, Bh>> {
+        ReadOnlyLock::new(self.inner.clone())
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub fn get_new_ref(&self, prev_block_hash: &Bh, current_block_hash: &Bh) -> Result<TreeQuery<P, BlockStateManager<P, S, Bh>>, BlockStateManagerError<Bh, S::Id>>
+        where
+            S::Id: SnapshotIdentity<Bh>,
+    {
+        let mut manager = self.inner.write().unwrap();
+        let snapshot_id = manager.register_block(prev_block_hash, current_block_hash)?;
+        let branch = manager.branch_token(current_block_hash);
+        Ok(TreeQuery::new(snapshot_id, manager.db.clone(), self.read_only(), branch)
+            .with_metrics(manager.metrics.clone()))
+    }
+
+    pub fn add_snapshot(&self, snapshot: S) -> Result<(), BlockStateManagerError<Bh, S::Id>>
+        where
+            S::Id: SnapshotIdentity<Bh>,
+    {
+        self.inner.write().unwrap().add_snapshot(snapshot)
+    }
+
+    pub fn finalize_snapshot(&self, block_hash: &Bh) -> Result<(), BlockStateManagerError<Bh, S::Id>> {
+        self.inner.write().unwrap().finalize_snapshot(block_hash)
+    }
+
+    pub fn finalize_up_to(&self, block_hash: &Bh) -> Result<Vec<Bh>, BlockStateManagerError<Bh, S::Id>> {
+        self.inner.write().unwrap().finalize_up_to(block_hash)
+    }
+
+    pub fn discard_branch(&self, block_hash: &Bh) -> Result<Vec<Bh>, BlockStateManagerError<Bh, S::Id>> {
+        self.inner.write().unwrap().discard_branch(block_hash)
+    }
+
+    pub fn compact_linear_chains(&self) -> usize {
+        self.inner.write().unwrap().compact_linear_chains()
+    }
+
+    pub fn set_limits(&self, limits: PendingLimits) {
+        self.inner.write().unwrap().set_limits(limits)
+    }
+
+    pub fn usage(&self) -> PendingUsage {
+        self.inner.read().unwrap().usage()
+    }
+
+    pub fn set_metrics(&self, metrics: Arc<dyn Metrics>) {
+        self.inner.write().unwrap().set_metrics(metrics)
+    }
+
+    pub fn repair_journal(&self) -> std::io::Result<()> {
+        self.inner.write().unwrap().repair_journal()
+    }
+
+    pub fn journal_failure(&self) -> Option<std::io::ErrorKind> {
+        self.inner.read().unwrap().journal_failure()
+    }
+
+    pub fn subscribe(&self) -> Receiver<ForkTreeEvent<Bh, S::Id, S::Key>> {
+        self.inner.write().unwrap().subscribe()
+    }
+}
+
+impl<P, S, Bh> BlockStateManagerHandle<P, S, Bh>
+    where
+        P: Storage<Key=S::Key, Value=S::Value>,
+        S: Snapshot + Into<P::Payload>,
+        S::Value: PartialEq,
+        Bh: Eq + Hash + Clone
+{
+    /// Read only handle for querying state after given block.
+    pub fn state_view(&self, block_hash: &Bh) -> Result<StateView<P, S, Bh>, BlockStateManagerError<Bh, S::Id>> {
+        self.inner.read().unwrap().check_readable(block_hash)?;
+        Ok(StateView {
+            block_hash: block_hash.clone(),
+            manager: self.read_only(),
+        })
+    }
+}
+
+/// Read only view of the state after a given block, pending or finalized.
+/// Unlike [`TreeQuery`], it does not register new block in the fork tree.
+pub struct StateView<P: Storage, S: Snapshot, Bh> {
+    block_hash: Bh,
+    manager: ReadOnlyLock<BlockStateManager<P, S, Bh>>,
+}
+
+impl<P, S, Bh> StateView<P, S, Bh>
+    where
+        P: Storage<Key=S::Key, Value=S::Value>,
+        S: Snapshot + Into<P::Payload>,
+        S::Value: PartialEq,
+        Bh: Eq + Hash + Clone
+{
+    pub fn block_hash(&self) -> &Bh {
+        &self.block_hash
+    }
+
+    /// Fails if block has been finalized past or discarded since view has been created.
+    pub fn get(&self, key: &S::Key) -> Result<Option<S::Value>, BlockStateManagerError<Bh, S::Id>> {
+        let manager = self.manager.read().unwrap();
+        manager.get_at(&self.block_hash, key)
+    }
+}
+
+// Opt-in compaction of linear chains
+impl<P, S, Bh> BlockStateManager<P, S, Bh>
+    where
+        P: Storage,
+        S: Snapshot + Into<P::Payload>,
+        Bh: Eq + Hash + Clone
+{
+    /// Merges writes of every chain of pending blocks, where each block except the newest has exactly one child,
+    /// into a single layer, so reads through the chain need one lookup instead of one per block.
+    /// Snapshots themselves are kept, so blocks can be finalized one by one.
+    /// Previous layers are rebuilt. Returns number of compacted chains.
+    pub fn compact_linear_chains(&mut self) -> usize {
+        // Cached lineages point to layers, which are about to be replaced
+        self.generation += 1;
+        for newest_block_hash in self.compacted_layers.keys().cloned().collect::<Vec<_>>() {
+            self.drop_compacted_layer(&newest_block_hash);
+        }
+
+        let mut chains = Vec::new();
+        for block_hash in self.pending_blocks() {
+            // Chain starts at block, which is not a single child with a snapshot of its parent
+            let continues_chain = self.blocks_to_parent.get(&block_hash)
+                .is_some_and(|parent| self.snapshots.contains_key(parent) && self.children_of(parent).len() == 1);
+            if continues_chain || !self.snapshots.contains_key(&block_hash) {
+                continue;
+            }
+            let mut chain = vec![block_hash.clone()];
+            let mut current_block_hash = block_hash;
+            while let [child] = self.children_of(&current_block_hash) {
+                if !self.snapshots.contains_key(child) {
+                    break;
+                }
+                chain.push(child.clone());
+                current_block_hash = child.clone();
+            }
+            if chain.len() > 1 {
+                chains.push(chain);
+            }
+        }
+
+        let compacted = chains.len();
+        for mut chain in chains {
+            let mut values = HashMap::new();
+            // From the oldest block, so newer writes replace older ones
+            for (idx, block_hash) in chain.iter().enumerate() {
+                let snapshot = &self.snapshots[block_hash];
+                let offset = chain.len() - 1 - idx;
+                for key in snapshot.written_keys() {
+                    // Deletions are kept, so they hide values of blocks before the chain
+                    if let Some(value) = snapshot.get_value(&key) {
+                        values.insert(key, CompactedValue { value, written_by: snapshot.get_id(), offset });
+                    }
+                }
+            }
+            let mut values_by_writer: HashMap<&S::Id, usize> = HashMap::new();
+            for compacted in values.values() {
+                *values_by_writer.entry(&compacted.written_by).or_default() += 1;
+            }
+            let bytes = chain.iter()
+                .map(|bh| &self.snapshots[bh])
+                .map(|snapshot| copied_bytes(snapshot, values_by_writer.get(&snapshot.get_id()).copied().unwrap_or_default()))
+                .sum();
+            self.pending_bytes += bytes;
+            chain.reverse();
+            let newest_block_hash = chain[0].clone();
+            for block_hash in &chain {
+                self.compacted_blocks.insert(block_hash.clone(), newest_block_hash.clone());
+            }
+            self.compacted_layers.insert(newest_block_hash, CompactedLayer { blocks: chain, values, bytes });
+        }
+        compacted
+    }
+}
+
+// Rendering of the pending fork tree, for debugging reorgs
+impl<P, S, Bh> BlockStateManager<P, S, Bh>
+    where
+        P: Storage,
+        S: Snapshot + Into<P::Payload>,
+        Bh: Eq + Hash + Clone + Display
+{
+    /// Graphviz representation of the pending fork tree.
+    pub fn render_dot(&self) -> String {
+        let mut dot = String::from("digraph fork_tree {\n");
+        for root in self.roots() {
+            dot.push_str(&format!("    \"{}\" [label=\"{}\\nfinalized\"];\n", root, root));
+        }
+        for block_hash in self.pending_blocks() {
+            let label = self.node_annotation(&block_hash).join("\\n");
+            dot.push_str(&format!("    \"{}\" [label=\"{}\\n{}\"];\n", block_hash, block_hash, label));
+        }
+        for block_hash in self.pending_blocks() {
+            if let Some(parent_block_hash) = self.parent_of(&block_hash) {
+                dot.push_str(&format!("    \"{}\" -> \"{}\";\n", parent_block_hash, block_hash));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Terminal friendly representation of the pending fork tree.
+    pub fn render_ascii(&self) -> String {
+        let mut output = String::new();
+        for root in self.roots() {
+            output.push_str(&format!("{} (finalized)\n", root));
+            self.render_ascii_children(root, "", &mut output);
+        }
+        output
+    }
+
+    fn render_ascii_children(&self, block_hash: &Bh, prefix: &str, output: &mut String) {
+        let children = self.children_of(block_hash);
+        for (idx, child) in children.iter().enumerate() {
+            let is_last = idx + 1 == children.len();
+            let (branch, continuation) = if is_last { ("└── ", "    ") } else { ("├── ", "│   ") };
+            let annotation = self.node_annotation(child).join(", ");
+            output.push_str(&format!("{}{}{} [{}]\n", prefix, branch, child, annotation));
+            self.render_ascii_children(child, &format!("{}{}", prefix, continuation), output);
+        }
+    }
+
+    fn node_annotation(&self, block_hash: &Bh) -> Vec<String> {
+        let mut annotation = Vec::new();
+        if let Some(snapshot_id) = self.snapshot_id_of(block_hash) {
+            annotation.push(format!("id={:?}", snapshot_id));
+        }
+        match self.snapshots.get(block_hash) {
+            Some(snapshot) => annotation.push(format!("{} writes", snapshot.writes_count())),
+            None => annotation.push("no snapshot".to_string()),
+        }
+        annotation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::BlockHash;
+    use crate::db::{Database, MeteredStorage};
+    use sov_first_read_last_write_cache::cache::CacheLog;
+    use sov_first_read_last_write_cache::CacheKey;
+    use crate::metrics::InMemoryMetrics;
+    use crate::state::{DB, FrozenSnapshot, StateCheckpoint};
+    use std::collections::BTreeMap;
+    use crate::types::{Key, Value};
+    use crate::witness::Witness;
+    use crate::test_utils::{bh, block_hashes, cache_key, cache_value, key, value};
+    use super::*;
+
+    fn write_values(db: DB, snapshot_ref: TreeQuery<Database, BlockStateManager<Database, FrozenSnapshot, BlockHash>>, values: &[(&str, &str)]) -> FrozenSnapshot {
+        let checkpoint = StateCheckpoint::new(snapshot_ref);
+        let mut working_set = checkpoint.into_revertable();
+        for (k, v) in values {
+            working_set.set(&key(k), value(v));
+        }
+        let checkpoint = working_set.commit();
+        let (_witness, snapshot) = checkpoint.freeze();
+        snapshot
+    }
+
+    fn add_block(state_manager: &BlockStateManagerHandle<Database, FrozenSnapshot, BlockHash>, db: &DB, prev_block_hash: &str, block_hash: &str, values: &[(&str, &str)]) {
+        let snapshot_ref = state_manager.get_new_ref(&prev_block_hash.to_string(), &block_hash.to_string()).unwrap();
+        let snapshot = write_values(db.clone(), snapshot_ref, values);
+        state_manager.add_snapshot(snapshot).unwrap();
+    }
+
+    mod fork_tree_manager {
+        use super::*;
+
+        #[test]
+        fn new() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::<Database, FrozenSnapshot, BlockHash>::new(db.clone());
+            {
+                let db = db.lock().unwrap();
+                assert!(db.data.is_empty());
+            }
+            assert!(state_manager.read().unwrap().is_empty());
+        }
+
+
+        #[test]
+        fn dropping_handle_releases_database() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            add_block(&state_manager, &db, "genesis", "a", &[("x", "1")]);
+            add_block(&state_manager, &db, "a", "b", &[("x", "2")]);
+            let snapshot_ref = state_manager.get_new_ref(&"b".to_string(), &"c".to_string()).unwrap();
+            let state_view = state_manager.state_view(&"b".to_string()).unwrap();
+            let other_handle = state_manager.clone();
+            state_manager.finalize_snapshot(&"a".to_string()).unwrap();
+            assert!(Arc::strong_count(&db) > 1);
+
+            drop(state_manager);
+            drop(other_handle);
+            drop(state_view);
+            // Query still can read, as it keeps manager alive
+            assert!(snapshot_ref.get_value_from_cache_layers(&cache_key("x")).unwrap().0.is_some());
+            assert!(Arc::strong_count(&db) > 1);
+
+            drop(snapshot_ref);
+            assert_eq!(1, Arc::strong_count(&db));
+        }
+
+        #[test]
+        fn reading_from_orphaned_branch() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            let x = key("x");
+
+            // genesis -> a -> b
+            //             \-> c -> d
+            add_block(&state_manager, &db, "genesis", "a", &[("x", "1")]);
+            add_block(&state_manager, &db, "a", "b", &[("x", "2")]);
+            add_block(&state_manager, &db, "a", "c", &[("x", "3")]);
+            let snapshot_ref_d = state_manager.get_new_ref(&bh("c"), &bh("d")).unwrap();
+            let snapshot_id_d = snapshot_ref_d.get_id();
+            let mut working_set_d = StateCheckpoint::new(snapshot_ref_d).into_revertable();
+            let snapshot_ref_e = state_manager.get_new_ref(&bh("b"), &bh("e")).unwrap();
+            let mut working_set_e = StateCheckpoint::new(snapshot_ref_e).into_revertable();
+            assert_eq!(Ok(Some(value("3"))), working_set_d.get(&x));
+
+            state_manager.finalize_snapshot(&bh("a")).unwrap();
+            state_manager.finalize_snapshot(&bh("b")).unwrap();
+
+            // D is on the pruned branch, and does not fall back to the database
+            assert_eq!(Err(QueryError::Orphaned(snapshot_id_d)), working_set_d.get(&key("y")));
+            assert_eq!(Ok(Some(value("2"))), working_set_e.get(&x));
+        }
+
+        #[test]
+        fn reading_from_discarded_branch() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            let x = cache_key("x");
+
+            add_block(&state_manager, &db, "genesis", "a", &[("x", "1")]);
+            add_block(&state_manager, &db, "a", "b", &[("x", "2")]);
+            let snapshot_ref_c = state_manager.get_new_ref(&bh("b"), &bh("c")).unwrap();
+            let snapshot_ref_d = state_manager.get_new_ref(&bh("a"), &bh("d")).unwrap();
+            assert!(!snapshot_ref_c.is_cancelled());
+
+            state_manager.discard_branch(&bh("b")).unwrap();
+            assert!(snapshot_ref_c.is_cancelled());
+            assert!(!snapshot_ref_d.is_cancelled());
+            assert_eq!(Err(QueryError::Orphaned(snapshot_ref_c.get_id())), snapshot_ref_c.get_value_from_cache_layers(&x));
+            let snapshot_id_a = state_manager.read().unwrap().snapshot_id_of(&bh("a")).unwrap();
+            assert_eq!(Ok((Some(cache_value("1")), ValueSource::Snapshot(snapshot_id_a))), snapshot_ref_d.get_value_from_cache_layers(&x));
+        }
+
+        #[test]
+        fn adding_cancelled_snapshot() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+
+            // genesis -> a -> b
+            //             \-> c (executing)
+            add_block(&state_manager, &db, "genesis", "a", &[("x", "1")]);
+            add_block(&state_manager, &db, "a", "b", &[("x", "2")]);
+            let snapshot_ref_c = state_manager.get_new_ref(&bh("a"), &bh("c")).unwrap();
+            let snapshot_id_c = snapshot_ref_c.get_id();
+            let mut checkpoint_c = StateCheckpoint::new(snapshot_ref_c);
+            assert!(!checkpoint_c.is_cancelled());
+
+            state_manager.finalize_up_to(&bh("b")).unwrap();
+            assert!(checkpoint_c.is_cancelled());
+            let mut working_set_c = checkpoint_c.into_revertable();
+            assert!(working_set_c.is_cancelled());
+            working_set_c.set(&key("x"), value("3"));
+            checkpoint_c = working_set_c.commit();
+            let (_, snapshot_c) = checkpoint_c.freeze();
+
+            assert_eq!(Err(BlockStateManagerError::CancelledSnapshot(snapshot_id_c)), state_manager.add_snapshot(snapshot_c));
+            assert!(state_manager.read().unwrap().is_empty());
+            assert_eq!(Some(value("2").to_string()), db.lock().unwrap().data.get("x").cloned());
+        }
+
+        #[test]
+        fn pruned_executions_leave_nothing_behind() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+
+            // genesis -> a -> b
+            //             \-> c_0 .. c_99 (executing, dropped without adding snapshot)
+            add_block(&state_manager, &db, "genesis", "a", &[("x", "1")]);
+            add_block(&state_manager, &db, "a", "b", &[("x", "2")]);
+            let snapshot_refs: Vec<_> = (0..100)
+                .map(|idx| state_manager.get_new_ref(&bh("a"), &bh(&format!("c_{}", idx))).unwrap())
+                .collect();
+            let stale_snapshot = write_values(db.clone(), state_manager.get_new_ref(&bh("a"), &bh("d")).unwrap(), &[("x", "3")]);
+            let stale_snapshot_id = stale_snapshot.get_id();
+
+            state_manager.finalize_up_to(&bh("b")).unwrap();
+            assert!(snapshot_refs.iter().all(|snapshot_ref| snapshot_ref.is_cancelled()));
+            drop(snapshot_refs);
+            assert!(state_manager.read().unwrap().is_empty());
+            assert_eq!(Err(BlockStateManagerError::CancelledSnapshot(stale_snapshot_id)), state_manager.add_snapshot(stale_snapshot));
+        }
+
+        #[test]
+        fn block_registered_again_after_discard() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::<Database, FrozenSnapshot<BlockHash>, BlockHash>::new(db.clone());
+            let execute = |snapshot_ref, v: &str| {
+                let mut working_set = StateCheckpoint::new(snapshot_ref).into_revertable();
+                working_set.set(&key("x"), value(v));
+                working_set.commit().freeze().1
+            };
+
+            let stale_snapshot_ref = state_manager.get_new_ref(&bh("genesis"), &bh("x")).unwrap();
+            state_manager.discard_branch(&bh("x")).unwrap();
+            let fresh_snapshot_ref = state_manager.get_new_ref(&bh("genesis"), &bh("x")).unwrap();
+            assert_eq!(stale_snapshot_ref.get_id(), fresh_snapshot_ref.get_id());
+            assert!(stale_snapshot_ref.is_cancelled());
+            assert!(!fresh_snapshot_ref.is_cancelled());
+
+            let stale_snapshot = execute(stale_snapshot_ref, "stale");
+            let fresh_snapshot = execute(fresh_snapshot_ref, "fresh");
+            state_manager.add_snapshot(fresh_snapshot).unwrap();
+            assert_eq!(Err(BlockStateManagerError::CancelledSnapshot(bh("x"))), state_manager.add_snapshot(stale_snapshot));
+            assert_eq!(Ok(Some(cache_value("fresh"))), state_manager.read().unwrap().get_at(&bh("x"), &cache_key("x")));
+        }
+
+        #[test]
+        fn snapshots_identified_by_block_hash() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::<Database, FrozenSnapshot<BlockHash>, BlockHash>::new(db.clone());
+            let x = key("x");
+
+            // genesis -> a -> b
+            //             \-> c
+            for (prev_block_hash, block_hash, v) in [("genesis", "a", "1"), ("a", "b", "2"), ("a", "c", "3")] {
+                let snapshot_ref = state_manager.get_new_ref(&bh(prev_block_hash), &bh(block_hash)).unwrap();
+                assert_eq!(bh(block_hash), snapshot_ref.get_id());
+                let mut working_set = StateCheckpoint::new(snapshot_ref).into_revertable();
+                working_set.set(&x, value(v));
+                let (_witness, snapshot) = working_set.commit().freeze();
+                assert_eq!(bh(block_hash), snapshot.get_id());
+                state_manager.add_snapshot(snapshot).unwrap();
+            }
+            {
+                let manager = state_manager.read().unwrap();
+                // Ids are block hashes, so nothing needs to be mapped
+                assert!(manager.snapshot_id_to_block_hash.is_empty());
+                let cache_key = CacheKey::from(x.clone());
+                assert_eq!(Some(cache_value("1")), manager.get_value_recursively(&bh("b"), &cache_key).flatten());
+                assert_eq!(Some(cache_value("3")), manager.get_value_inclusive(&bh("c"), &cache_key).flatten());
+            }
+
+            state_manager.finalize_up_to(&bh("b")).unwrap();
+            assert_eq!(Some(&"2".to_string()), db.lock().unwrap().data.get("x"));
+
+            let snapshot_ref_d = state_manager.get_new_ref(&bh("b"), &bh("d")).unwrap();
+            let mut working_set_d = StateCheckpoint::new(snapshot_ref_d).into_revertable();
+            assert_eq!(Ok(Some(value("2"))), working_set_d.get(&x));
+            assert_eq!(Err(BlockStateManagerError::DuplicateBlock(bh("d"))), state_manager.get_new_ref(&bh("b"), &bh("d")).map(|_| ()));
+        }
+
+        fn journal_path(name: &str) -> std::path::PathBuf {
+            let path = std::env::temp_dir().join(format!("block_state_manager_{}_{}.journal", name, std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            path
+        }
+
+        #[test]
+        fn recover_pending_tree_from_journal() {
+            let db = DB::default();
+            let path = journal_path("recover");
+            let x = cache_key("x");
+
+            let state_manager = BlockStateManagerHandle::recover(&path, db.clone()).unwrap();
+            assert!(state_manager.read().unwrap().is_empty());
+            // genesis -> a -> b
+            //             \-> c -> d
+            //                  \-> e
+            add_block(&state_manager, &db, "genesis", "a", &[("x", "1")]);
+            add_block(&state_manager, &db, "a", "b", &[("x", "2")]);
+            add_block(&state_manager, &db, "a", "c", &[("y", "3")]);
+            state_manager.finalize_snapshot(&bh("a")).unwrap();
+            add_block(&state_manager, &db, "c", "d", &[("x", "4")]);
+            add_block(&state_manager, &db, "c", "e", &[]);
+            // Still executing, so it is lost after restart
+            let snapshot_ref_f = state_manager.get_new_ref(&bh("d"), &bh("f")).unwrap();
+            state_manager.discard_branch(&bh("e")).unwrap();
+            let expected_tree = "\
+a (finalized)
+├── b [id=2, 1 writes]
+└── c [id=3, 1 writes]
+    └── d [id=4, 1 writes]
+";
+            drop(snapshot_ref_f);
+            drop(state_manager);
+
+            let state_manager = BlockStateManagerHandle::<Database, FrozenSnapshot, BlockHash>::recover(&path, db.clone()).unwrap();
+            {
+                let manager = state_manager.read().unwrap();
+                assert_eq!(expected_tree, manager.render_ascii());
+                assert_eq!(block_hashes(&["b", "c", "d"]), manager.pending_blocks());
+                assert_eq!(Some(cache_value("4")), manager.get_value_inclusive(&4, &x).flatten());
+                assert_eq!(Some(cache_value("3")), manager.get_value_recursively(&4, &cache_key("y")).flatten());
+            }
+            let snapshot_ref_g = state_manager.get_new_ref(&bh("d"), &bh("g")).unwrap();
+            assert_eq!(7, snapshot_ref_g.get_id());
+            assert_eq!(Err(BlockStateManagerError::DuplicateBlock(bh("a"))), state_manager.get_new_ref(&bh("genesis"), &bh("a")).map(|_| ()));
+
+            state_manager.finalize_up_to(&bh("d")).unwrap();
+            assert_eq!(Some(&"4".to_string()), db.lock().unwrap().data.get("x"));
+            assert_eq!(Some(&"3".to_string()), db.lock().unwrap().data.get("y"));
+            drop(snapshot_ref_g);
+            drop(state_manager);
+
+            let state_manager = BlockStateManagerHandle::<Database, FrozenSnapshot, BlockHash>::recover(&path, db.clone()).unwrap();
+            assert!(state_manager.read().unwrap().is_empty());
+            assert_eq!(Err(BlockStateManagerError::DuplicateBlock(bh("d"))), state_manager.get_new_ref(&bh("c"), &bh("d")).map(|_| ()));
+            assert_eq!(8, state_manager.get_new_ref(&bh("d"), &bh("h")).unwrap().get_id());
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        #[test]
+        fn recover_from_torn_journal() {
+            let db = DB::default();
+            let path = journal_path("torn");
+
+            let state_manager = BlockStateManagerHandle::recover(&path, db.clone()).unwrap();
+            add_block(&state_manager, &db, "genesis", "a", &[("x", "1")]);
+            add_block(&state_manager, &db, "a", "b", &[("x", "2")]);
+            drop(state_manager);
+            // Crash in the middle of writing next record
+            let mut journal = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+            std::io::Write::write_all(&mut journal, &[100, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3]).unwrap();
+            drop(journal);
+
+            let state_manager = BlockStateManagerHandle::recover(&path, db.clone()).unwrap();
+            assert_eq!(block_hashes(&["a", "b"]), state_manager.read().unwrap().pending_blocks());
+            add_block(&state_manager, &db, "b", "c", &[("x", "3")]);
+            drop(state_manager);
+
+            let state_manager = BlockStateManagerHandle::<Database, FrozenSnapshot, BlockHash>::recover(&path, db.clone()).unwrap();
+            assert_eq!(block_hashes(&["a", "b", "c"]), state_manager.read().unwrap().pending_blocks());
+            state_manager.finalize_up_to(&bh("c")).unwrap();
+            assert_eq!(Some(&"3".to_string()), db.lock().unwrap().data.get("x"));
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        /// Journal, which fails while `failing` is set, and counts successful writes and rewrites among them.
+        #[derive(Debug, Default)]
+        struct FlakyJournal {
+            failing: Arc<std::sync::atomic::AtomicBool>,
+            writes: Arc<std::sync::atomic::AtomicUsize>,
+            rewrites: Arc<std::sync::atomic::AtomicUsize>,
+        }
+
+        impl FlakyJournal {
+            fn write(&self) -> std::io::Result<()> {
+                if self.failing.load(std::sync::atomic::Ordering::SeqCst) {
+                    return Err(std::io::Error::from(std::io::ErrorKind::StorageFull));
+                }
+                self.writes.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Ok(())
+            }
+        }
+
+        impl<Bh, S: Snapshot> Journal<Bh, S> for FlakyJournal {
+            fn snapshot_added(&mut self, _parent_block_hash: &Bh, _block_hash: &Bh, _snapshot: &S, _latest_snapshot_id: Option<&S::Id>) -> std::io::Result<()> {
+                self.write()
+            }
+
+            fn blocks_finalized(&mut self, _block_hashes: &[Bh], _latest_snapshot_id: Option<&S::Id>) -> std::io::Result<()> {
+                self.write()
+            }
+
+            fn branch_discarded(&mut self, _block_hash: &Bh, _latest_snapshot_id: Option<&S::Id>) -> std::io::Result<()> {
+                self.write()
+            }
+
+            fn rewrite(&mut self, _last_finalized_block: Option<&Bh>, _latest_snapshot_id: Option<&S::Id>, _blocks: &[(&Bh, &Bh, &S)]) -> std::io::Result<()> {
+                self.write()?;
+                self.rewrites.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Ok(())
+            }
+        }
+
+        #[test]
+        fn journal_is_appended_and_rewritten_once_it_grows() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            let journal = FlakyJournal::default();
+            let writes = journal.writes.clone();
+            let rewrites = journal.rewrites.clone();
+            state_manager.write().unwrap().journal = Some(Box::new(journal));
+
+            let mut prev_block_hash = bh("genesis");
+            // Each block adds 2 records: when its snapshot is added and when it is finalized
+            for height in 0..JOURNAL_REWRITE_RECORDS / 2 {
+                let block_hash = format!("block_{}", height);
+                add_block(&state_manager, &db, &prev_block_hash, &block_hash, &[("x", "1")]);
+                state_manager.finalize_snapshot(&block_hash).unwrap();
+                prev_block_hash = block_hash;
+            }
+            assert_eq!(JOURNAL_REWRITE_RECORDS + 1, writes.load(std::sync::atomic::Ordering::SeqCst));
+            assert_eq!(1, rewrites.load(std::sync::atomic::Ordering::SeqCst));
+            assert_eq!(1, state_manager.read().unwrap().journal_records);
+        }
+
+        #[test]
+        fn journal_failure_does_not_fail_applied_changes() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            let journal = FlakyJournal::default();
+            let failing = journal.failing.clone();
+            let writes = journal.writes.clone();
+            state_manager.write().unwrap().journal = Some(Box::new(journal));
+
+            failing.store(true, std::sync::atomic::Ordering::SeqCst);
+            // Executed block is kept, even if it could not be journaled
+            add_block(&state_manager, &db, "genesis", "a", &[("x", "1")]);
+            assert_eq!(Some(std::io::ErrorKind::StorageFull), state_manager.journal_failure());
+            assert!(state_manager.read().unwrap().has_snapshot(&bh("a")));
+            add_block(&state_manager, &db, "a", "b", &[("x", "2")]);
+            // Finalization is done, so it is not reported as failed
+            assert_eq!(Ok(()), state_manager.finalize_snapshot(&bh("a")));
+            assert_eq!(Some("1".to_string()), db.lock().unwrap().get("x"));
+            assert_eq!(0, writes.load(std::sync::atomic::Ordering::SeqCst));
+
+            // Nothing is appended to the journal, which is behind, until it is repaired
+            failing.store(false, std::sync::atomic::Ordering::SeqCst);
+            add_block(&state_manager, &db, "b", "c", &[("x", "3")]);
+            assert_eq!(Some(std::io::ErrorKind::StorageFull), state_manager.journal_failure());
+            assert_eq!(0, writes.load(std::sync::atomic::Ordering::SeqCst));
+            state_manager.repair_journal().unwrap();
+            assert_eq!(None, state_manager.journal_failure());
+            assert_eq!(1, writes.load(std::sync::atomic::Ordering::SeqCst));
+            // Changes after repair are appended
+            assert_eq!(Ok(vec![bh("b"), bh("c")]), state_manager.finalize_up_to(&bh("c")));
+            assert_eq!(2, writes.load(std::sync::atomic::Ordering::SeqCst));
+        }
+
+        #[test]
+        fn back_pressure_on_pending_limits() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            state_manager.set_limits(PendingLimits {
+                max_blocks: Some(3),
+                max_bytes: Some(6),
+            });
+
+            add_block(&state_manager, &db, "genesis", "a", &[("x", "1")]);
+            add_block(&state_manager, &db, "a", "b", &[("x", "22")]);
+            assert_eq!(PendingUsage { blocks: 2, bytes: 5 }, state_manager.usage());
+            let snapshot_ref_c = state_manager.get_new_ref(&bh("b"), &bh("c")).unwrap();
+            assert_eq!(PendingUsage { blocks: 3, bytes: 5 }, state_manager.usage());
+            assert_eq!(
+                Err(BlockStateManagerError::BackPressure(PendingUsage { blocks: 3, bytes: 5 })),
+                state_manager.get_new_ref(&bh("b"), &bh("d")).map(|_| ()),
+            );
+
+            state_manager.finalize_snapshot(&bh("a")).unwrap();
+            assert_eq!(PendingUsage { blocks: 2, bytes: 3 }, state_manager.usage());
+            let snapshot = write_values(db.clone(), snapshot_ref_c, &[("y", "333")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+            assert_eq!(PendingUsage { blocks: 2, bytes: 7 }, state_manager.usage());
+            assert_eq!(
+                Err(BlockStateManagerError::BackPressure(PendingUsage { blocks: 2, bytes: 7 })),
+                state_manager.get_new_ref(&bh("c"), &bh("d")).map(|_| ()),
+            );
+
+            state_manager.discard_branch(&bh("c")).unwrap();
+            assert_eq!(PendingUsage { blocks: 1, bytes: 3 }, state_manager.usage());
+            state_manager.get_new_ref(&bh("b"), &bh("d")).unwrap();
+
+            state_manager.set_limits(PendingLimits::default());
+            state_manager.get_new_ref(&bh("b"), &bh("e")).unwrap();
+            state_manager.get_new_ref(&bh("b"), &bh("f")).unwrap();
+            assert_eq!(PendingUsage { blocks: 4, bytes: 3 }, state_manager.usage());
+        }
+
+        #[test]
+        fn metrics_are_recorded() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            let metrics = Arc::new(InMemoryMetrics::default());
+            state_manager.set_metrics(metrics.clone());
+
+            // genesis -> a -> b -> d
+            //             \-> c
+            add_block(&state_manager, &db, "genesis", "a", &[("x", "1"), ("y", "1")]);
+            add_block(&state_manager, &db, "a", "b", &[("x", "2")]);
+            add_block(&state_manager, &db, "a", "c", &[("z", "3")]);
+            let snapshot_ref_d = state_manager.get_new_ref(&bh("b"), &bh("d")).unwrap();
+            let mut working_set = StateCheckpoint::new(snapshot_ref_d).into_revertable();
+            working_set.get(&key("x")).unwrap();
+            working_set.get(&key("y")).unwrap();
+            working_set.get(&key("z")).unwrap();
+            working_set.set(&key("foo"), value("bar"));
+            let (_, snapshot) = working_set.revert().freeze();
+            state_manager.add_snapshot(snapshot).unwrap();
+
+            let recorded = metrics.recorded();
+            assert_eq!(4, recorded.pending_snapshots);
+            assert_eq!(4, recorded.forks_created);
+            assert_eq!(BTreeMap::from([(1, 1), (2, 1)]), recorded.cache_layer_hits);
+            assert_eq!(1, recorded.storage_fallbacks);
+            assert_eq!(3, recorded.working_set_reads);
+            // 4 from creating the blocks above
+            assert_eq!(5, recorded.working_set_writes);
+            assert_eq!(1, recorded.working_set_reverts);
+
+            state_manager.finalize_up_to(&bh("b")).unwrap();
+            let recorded = metrics.recorded();
+            assert_eq!(1, recorded.pending_snapshots);
+            assert_eq!(2, recorded.blocks_finalized);
+            assert_eq!(1, recorded.blocks_discarded);
+            // Commits are recorded by the storage itself, see `MeteredStorage`
+            assert_eq!(0, recorded.storage_commits);
+
+            state_manager.discard_branch(&bh("d")).unwrap();
+            let recorded = metrics.recorded();
+            assert_eq!(0, recorded.pending_snapshots);
+            assert_eq!(2, recorded.blocks_discarded);
+
+            // Query keeps recording to metrics it has been created with, both hits and fallbacks
+            add_block(&state_manager, &db, "b", "e", &[("x", "5")]);
+            let snapshot_ref_f = state_manager.get_new_ref(&bh("e"), &bh("f")).unwrap();
+            let other_metrics = Arc::new(InMemoryMetrics::default());
+            state_manager.set_metrics(other_metrics.clone());
+            snapshot_ref_f.get_value_from_cache_layers(&cache_key("x")).unwrap();
+            snapshot_ref_f.get_value_from_cache_layers(&cache_key("w")).unwrap();
+            let recorded = metrics.recorded();
+            assert_eq!(BTreeMap::from([(1, 2), (2, 1)]), recorded.cache_layer_hits);
+            assert_eq!(2, recorded.storage_fallbacks);
+
+            // Reads of the state after a block are recorded too
+            assert_eq!(Ok(Some(cache_value("5"))), state_manager.read().unwrap().get_at(&bh("e"), &cache_key("x")));
+            assert_eq!(Ok(None), state_manager.read().unwrap().get_at(&bh("e"), &cache_key("w")));
+            let recorded = other_metrics.recorded();
+            assert_eq!(BTreeMap::from([(0, 1)]), recorded.cache_layer_hits);
+            assert_eq!(1, recorded.storage_fallbacks);
+
+            // Compacted chain reports depth of the block, which has written the value, not of the chain
+            state_manager.add_snapshot(write_values(db.clone(), snapshot_ref_f, &[("y", "7")])).unwrap();
+            assert_eq!(1, state_manager.compact_linear_chains());
+            assert_eq!(Ok(Some(cache_value("5"))), state_manager.read().unwrap().get_at(&bh("f"), &cache_key("x")));
+            // Traversals of the manager itself are not reads
+            assert_eq!(Some(vec![cache_key("y")]), state_manager.read().unwrap().diff_between(&bh("e"), &bh("f")));
+            let recorded = other_metrics.recorded();
+            assert_eq!(BTreeMap::from([(0, 1), (1, 1)]), recorded.cache_layer_hits);
+            assert_eq!(1, recorded.storage_fallbacks);
+        }
+
+        #[test]
+        fn storage_commits_are_recorded_by_storage() {
+            let metrics = Arc::new(InMemoryMetrics::default());
+            let db = Arc::new(Mutex::new(MeteredStorage::new(Database::default(), metrics.clone())));
+            let state_manager = BlockStateManagerHandle::<_, FrozenSnapshot, BlockHash>::new(db.clone());
+            for (prev_block_hash, block_hash, values) in [("genesis", "a", vec![("x", "1"), ("y", "1")]), ("a", "b", vec![("x", "2")])] {
+                let snapshot_ref = state_manager.get_new_ref(&bh(prev_block_hash), &bh(block_hash)).unwrap();
+                let mut working_set = StateCheckpoint::new(snapshot_ref).into_revertable();
+                for (k, v) in values {
+                    working_set.set(&key(k), value(v));
+                }
+                state_manager.add_snapshot(working_set.commit().freeze().1).unwrap();
+            }
+
+            state_manager.finalize_up_to(&bh("b")).unwrap();
+            let recorded = metrics.recorded();
+            assert_eq!(2, recorded.storage_commits);
+            assert_eq!(3, recorded.keys_committed);
+
+            // Commits, which do not go through the manager, are recorded as well
+            let mut cache_log = CacheLog::default();
+            cache_log.add_write(cache_key("z"), Some(cache_value("3")));
+            db.lock().unwrap().commit(cache_log);
+            let recorded = metrics.recorded();
+            assert_eq!(3, recorded.storage_commits);
+            assert_eq!(4, recorded.keys_committed);
+            assert_eq!(Some("3".to_string()), db.lock().unwrap().inner().get("z"));
+        }
+
+        #[test]
+        fn fork_tree_events() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            let events = state_manager.subscribe();
+            let dropped_events = state_manager.subscribe();
+            let keys = |keys: &[&str]| keys.iter().map(|k| cache_key(k)).collect::<Vec<_>>();
+
+            // genesis -> a -> b -> d
+            //             \-> c -> e
+            add_block(&state_manager, &db, "genesis", "a", &[("x", "1"), ("w", "1"), ("v", "1")]);
+            drop(dropped_events);
+            add_block(&state_manager, &db, "a", "b", &[("y", "2")]);
+            add_block(&state_manager, &db, "a", "c", &[("x", "3")]);
+            add_block(&state_manager, &db, "c", "e", &[]);
+            let _snapshot_ref_d = state_manager.get_new_ref(&bh("b"), &bh("d")).unwrap();
+            assert_eq!(1, state_manager.read().unwrap().subscribers.len());
+
+            state_manager.finalize_up_to(&bh("b")).unwrap();
+            state_manager.discard_branch(&bh("d")).unwrap();
+
+            let expected_events = vec![
+                ForkTreeEvent::SnapshotAdded { block: bh("a"), id: 1 },
+                ForkTreeEvent::SnapshotAdded { block: bh("b"), id: 2 },
+                ForkTreeEvent::SnapshotAdded { block: bh("c"), id: 3 },
+                ForkTreeEvent::SnapshotAdded { block: bh("e"), id: 4 },
+                ForkTreeEvent::Finalized { block: bh("a"), written_keys: keys(&["v", "w", "x"]) },
+                ForkTreeEvent::Finalized { block: bh("b"), written_keys: keys(&["y"]) },
+                ForkTreeEvent::Discarded { blocks: block_hashes(&["c", "e"]) },
+                ForkTreeEvent::Discarded { blocks: block_hashes(&["d"]) },
+            ];
+            assert_eq!(expected_events, events.try_iter().collect::<Vec<_>>());
+        }
+
+        #[test]
+        fn deleted_key_is_hidden_from_child_forks() {
+            let db = DB::default();
+            db.lock().unwrap().set("x", "0".to_string());
+            db.lock().unwrap().set("y", "0".to_string());
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            let x = key("x");
+            let y = key("y");
+
+            // genesis -> a -> b -> d
+            //        \-> c
+            let snapshot_ref_a = state_manager.get_new_ref(&bh("genesis"), &bh("a")).unwrap();
+            let mut working_set_a = StateCheckpoint::new(snapshot_ref_a).into_revertable();
+            working_set_a.set(&x, value("1"));
+            working_set_a.delete(&x);
+            assert_eq!(Ok(None), working_set_a.get(&x));
+            let (witness, snapshot_a) = working_set_a.commit().freeze();
+            // Only own writes have been read
+            assert_eq!(0, witness.len());
+            assert_eq!(Some(None), snapshot_a.get_value(&CacheKey::from(x.clone())));
+            state_manager.add_snapshot(snapshot_a).unwrap();
+
+            let snapshot_ref_b = state_manager.get_new_ref(&bh("a"), &bh("b")).unwrap();
+            let mut working_set_b = StateCheckpoint::new(snapshot_ref_b).into_revertable();
+            assert_eq!(Ok(None), working_set_b.get(&x));
+            working_set_b.delete(&y);
+            let mut checkpoint_b = working_set_b.commit();
+            let mut working_set_b = checkpoint_b.into_revertable();
+            // Deleted in the previous transaction
+            assert_eq!(Ok(None), working_set_b.get(&y));
+            working_set_b.set(&y, value("2"));
+            assert_eq!(Ok(Some(value("2"))), working_set_b.get(&y));
+            checkpoint_b = working_set_b.commit();
+            let (_, snapshot_b) = checkpoint_b.freeze();
+            state_manager.add_snapshot(snapshot_b).unwrap();
+            add_block(&state_manager, &db, "genesis", "c", &[]);
+
+            let snapshot_ref_d = state_manager.get_new_ref(&bh("b"), &bh("d")).unwrap();
+            let mut working_set_d = StateCheckpoint::new(snapshot_ref_d).into_revertable();
+            assert_eq!(Ok(None), working_set_d.get(&x));
+            assert_eq!(Ok(Some(value("2"))), working_set_d.get(&y));
+            assert_eq!(Ok(Some(value("0"))), state_manager.state_view(&bh("c")).unwrap().get(&CacheKey::from(x.clone())).map(|value| value.map(Value::from)));
+            assert_eq!(Ok(None), state_manager.state_view(&bh("b")).unwrap().get(&CacheKey::from(x.clone())));
+
+            assert_eq!(1, state_manager.compact_linear_chains());
+            let snapshot_ref_e = state_manager.get_new_ref(&bh("b"), &bh("e")).unwrap();
+            let mut working_set_e = StateCheckpoint::new(snapshot_ref_e).into_revertable();
+            assert_eq!(Ok(None), working_set_e.get(&x));
+
+            state_manager.finalize_up_to(&bh("b")).unwrap();
+            assert_eq!(None, db.lock().unwrap().get("x"));
+            assert_eq!(Some("2".to_string()), db.lock().unwrap().get("y"));
+        }
+
+        #[test]
+        fn witness_tracks_first_reads_outside_of_block() {
+            let db = DB::default();
+            db.lock().unwrap().set("w", "0".to_string());
+            let state_manager = BlockStateManagerHandle::<Database, FrozenSnapshot, BlockHash>::new(db.clone());
+            let genesis_block = "genesis".to_string();
+            let block_a = "a".to_string();
+            let block_b = "b".to_string();
+            let entries = |witness: &Witness| -> Vec<(String, Option<Value>, ValueSource)> {
+                witness.entries().into_iter().map(|(key, value, source)| (key.to_string(), value, source)).collect()
+            };
+
+            // Block A only writes
+            let snapshot_ref = state_manager.get_new_ref(&genesis_block, &block_a).unwrap();
+            let mut working_set = StateCheckpoint::new(snapshot_ref).into_revertable();
+            working_set.set(&key("x"), value("1"));
+            working_set.set(&key("y"), value("2"));
+            assert_eq!(Ok(Some(value("1"))), working_set.get(&key("x")));
+            let (witness, snapshot) = working_set.commit().freeze();
+            assert!(entries(&witness).is_empty());
+            let snapshot_id_a = snapshot.get_id();
+            state_manager.add_snapshot(snapshot).unwrap();
+
+            // Block B reads from A and from storage
+            let snapshot_ref = state_manager.get_new_ref(&block_a, &block_b).unwrap();
+            let mut working_set = StateCheckpoint::new(snapshot_ref).into_revertable();
+            assert_eq!(Ok(Some(value("1"))), working_set.get(&key("x")));
+            assert_eq!(Ok(Some(value("1"))), working_set.get(&key("x")));
+            assert_eq!(Ok(None), working_set.get(&key("z")));
+            working_set.set(&key("x"), value("3"));
+            working_set.set(&key("y"), value("4"));
+            assert_eq!(Ok(Some(value("4"))), working_set.get(&key("y")));
+            let mut working_set = working_set.commit().into_revertable();
+            // Reads of reverted transaction are still in the witness, and are not repeated later
+            assert_eq!(Ok(Some(value("0"))), working_set.get(&key("w")));
+            let mut working_set = working_set.revert().into_revertable();
+            assert_eq!(Ok(Some(value("0"))), working_set.get(&key("w")));
+            assert_eq!(Ok(None), working_set.get(&key("z")));
+            assert_eq!(Ok(Some(value("3"))), working_set.get(&key("x")));
+            let (witness, snapshot) = working_set.commit().freeze();
+
+            assert_eq!(vec![
+                ("x".to_string(), Some(value("1")), ValueSource::Snapshot(snapshot_id_a)),
+                ("z".to_string(), None, ValueSource::Storage),
+                ("w".to_string(), Some(value("0")), ValueSource::Storage),
+            ], entries(&witness));
+            let mut written_keys: Vec<String> = snapshot.written_keys().into_iter().map(|key| Key::from(key).to_string()).collect();
+            written_keys.sort();
+            assert_eq!(vec!["x".to_string(), "y".to_string()], written_keys);
+        }
+
+        #[test]
+        fn linear_progression_with_2_blocks_delay() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            assert!(state_manager.read().unwrap().is_empty());
+            let genesis_block = "genesis".to_string();
+            let block_a = "a".to_string();
+            let block_b = "b".to_string();
+            let block_c = "c".to_string();
+
+            // Block A
+            let block_a_values = vec![
+                ("x", "1"),
+                ("y", "2"),
+            ];
+            let snapshot_ref = state_manager.get_new_ref(&genesis_block, &block_a).unwrap();
+
+            assert!(!state_manager.read().unwrap().is_empty());
+            let snapshot = write_values(db.clone(), snapshot_ref, &block_a_values);
+            state_manager.add_snapshot(snapshot).unwrap();
+            assert!(!state_manager.read().unwrap().is_empty());
+            {
+                assert!(db.lock().unwrap().data.is_empty());
+            }
+
+            // Block B
+            let block_b_values = vec![
+                ("x", "3"),
+                ("z", "4"),
+            ];
+            let snapshot_ref = state_manager.get_new_ref(&block_a, &block_b).unwrap();
+            let snapshot = write_values(db.clone(), snapshot_ref, &block_b_values);
+            let snapshot_id_b = snapshot.get_id();
+            state_manager.add_snapshot(snapshot).unwrap();
+            assert_eq!(Some(cache_value("1")), state_manager.read().unwrap().get_value_recursively(&snapshot_id_b, &cache_key("x")).flatten());
+            {
+                assert!(db.lock().unwrap().data.is_empty());
+            }
+            println!("AFTER B:\n{}", state_manager.read().unwrap().render_ascii());
+            // Finalizing A
+            state_manager.finalize_snapshot(&block_a).unwrap();
+            {
+                let db = db.lock().unwrap();
+                assert!(!db.data.is_empty());
+                assert_eq!(Some("1".to_string()), db.get("x"));
+                assert_eq!(Some("2".to_string()), db.get("y"));
+                assert_eq!(None, db.get("z"));
+            }
+            println!("AFTER FINALIZING A:\n{}", state_manager.read().unwrap().render_ascii());
+
+            // Block C
+            let block_c_values = vec![
+                ("x", "5"),
+                ("z", "6"),
+            ];
+            let snapshot_ref = state_manager.get_new_ref(&block_b, &block_c).unwrap();
+            let snapshot = write_values(db.clone(), snapshot_ref, &block_c_values);
+            state_manager.add_snapshot(snapshot).unwrap();
+            println!("AFTER C:\n{}", state_manager.read().unwrap().render_ascii());
+            // Finalizing B
+            state_manager.finalize_snapshot(&block_b).unwrap();
+            assert!(!state_manager.read().unwrap().is_empty());
+            {
+                let db = db.lock().unwrap();
+                assert!(!db.data.is_empty());
+                assert_eq!(Some("3".to_string()), db.get("x"));
+                assert_eq!(Some("2".to_string()), db.get("y"));
+                assert_eq!(Some("4".to_string()), db.get("z"));
+            }
+
+            state_manager.finalize_snapshot(&block_c).unwrap();
+            // TODO: Finalize everything, it should be clean
+            println!("AFTER FINALIZING C:\n{}", state_manager.read().unwrap().render_ascii());
+            assert!(state_manager.read().unwrap().is_empty());
+        }
+
+        #[test]
+        fn fork_added() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            let genesis_block = "genesis".to_string();
+            let block_a = "a".to_string();
+            let block_b = "b".to_string();
+            let block_c = "c".to_string();
+
+            add_block(&state_manager, &db, "genesis", "a", &[("x", "1")]);
+            add_block(&state_manager, &db, "a", "b", &[("x", "2")]);
+            add_block(&state_manager, &db, "a", "c", &[("x", "3")]);
+
+            assert_eq!(&block_hashes(&["b", "c"])[..], state_manager.read().unwrap().children_of(&block_a));
+            assert_eq!(Some(&block_a), state_manager.read().unwrap().parent_of(&block_b));
+            assert_eq!(Some(&block_a), state_manager.read().unwrap().parent_of(&block_c));
+            assert_eq!(Some(&genesis_block), state_manager.read().unwrap().parent_of(&block_a));
+            assert_eq!(None, state_manager.read().unwrap().parent_of(&genesis_block));
+            assert_eq!(block_hashes(&["b", "c"]), state_manager.read().unwrap().tips());
+            assert_eq!(2, state_manager.read().unwrap().depth());
+
+            let x = cache_key("x");
+            let snapshot_ref = state_manager.get_new_ref(&block_c, &"d".to_string()).unwrap();
+            assert_eq!(Some(cache_value("3")), state_manager.read().unwrap().get_value_recursively(&snapshot_ref.get_id(), &x).flatten());
+            let snapshot_ref = state_manager.get_new_ref(&block_b, &"e".to_string()).unwrap();
+            assert_eq!(Some(cache_value("2")), state_manager.read().unwrap().get_value_recursively(&snapshot_ref.get_id(), &x).flatten());
+        }
+
+        #[test]
+        fn common_ancestor_and_diff() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            db.lock().unwrap().set("w", "0".to_string());
+
+            // a -> b -> c -> d
+            //       \-> e -> f
+            add_block(&state_manager, &db, "a", "b", &[("x", "1"), ("y", "1")]);
+            add_block(&state_manager, &db, "b", "c", &[("x", "2"), ("z", "2")]);
+            add_block(&state_manager, &db, "c", "d", &[("u", "3")]);
+            add_block(&state_manager, &db, "b", "e", &[("x", "2"), ("y", "4")]);
+            add_block(&state_manager, &db, "e", "f", &[("u", "3"), ("w", "5")]);
+
+            assert_eq!(Some(bh("b")), state_manager.read().unwrap().common_ancestor(&bh("d"), &bh("f")));
+            assert_eq!(Some(bh("b")), state_manager.read().unwrap().common_ancestor(&bh("c"), &bh("e")));
+            assert_eq!(Some(bh("c")), state_manager.read().unwrap().common_ancestor(&bh("d"), &bh("c")));
+            assert_eq!(Some(bh("d")), state_manager.read().unwrap().common_ancestor(&bh("d"), &bh("d")));
+            assert_eq!(Some(bh("a")), state_manager.read().unwrap().common_ancestor(&bh("a"), &bh("f")));
+            assert_eq!(None, state_manager.read().unwrap().common_ancestor(&bh("d"), &bh("unknown")));
+
+            // x and u have been written on both sides with the same value
+            let mut diff = state_manager.read().unwrap().diff_between(&bh("d"), &bh("f")).unwrap();
+            diff.sort();
+            assert_eq!(vec![cache_key("w"), cache_key("y"), cache_key("z")], diff);
+            let diff = state_manager.read().unwrap().diff_between(&bh("c"), &bh("d")).unwrap();
+            assert_eq!(vec![cache_key("u")], diff);
+            assert!(state_manager.read().unwrap().diff_between(&bh("d"), &bh("d")).unwrap().is_empty());
+            assert_eq!(None, state_manager.read().unwrap().diff_between(&bh("d"), &bh("unknown")));
+        }
+
+        #[test]
+        fn recursive_and_inclusive_lookup() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+
+            let snapshot_ref_a = state_manager.get_new_ref(&"genesis".to_string(), &"a".to_string()).unwrap();
+            let snapshot_id_a = snapshot_ref_a.get_id();
+            let snapshot = write_values(db.clone(), snapshot_ref_a, &[("x", "1"), ("y", "1")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+            let snapshot_ref_b = state_manager.get_new_ref(&"a".to_string(), &"b".to_string()).unwrap();
+            let snapshot_id_b = snapshot_ref_b.get_id();
+
+            // B is still executing, so both modes see A's values
+            assert_eq!(Some(cache_value("1")), state_manager.read().unwrap().get_value_recursively(&snapshot_id_b, &cache_key("x")).flatten());
+            assert_eq!(Some(cache_value("1")), state_manager.read().unwrap().get_value_inclusive(&snapshot_id_b, &cache_key("x")).flatten());
+
+            let snapshot = write_values(db.clone(), snapshot_ref_b, &[("x", "2")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+
+            assert_eq!(Some(cache_value("1")), state_manager.read().unwrap().get_value_recursively(&snapshot_id_b, &cache_key("x")).flatten());
+            assert_eq!(Some(cache_value("2")), state_manager.read().unwrap().get_value_inclusive(&snapshot_id_b, &cache_key("x")).flatten());
+            assert_eq!(Some(cache_value("1")), state_manager.read().unwrap().get_value_inclusive(&snapshot_id_b, &cache_key("y")).flatten());
+            assert_eq!(None, state_manager.read().unwrap().get_value_recursively(&snapshot_id_a, &cache_key("x")).flatten());
+            assert_eq!(Some(cache_value("1")), state_manager.read().unwrap().get_value_inclusive(&snapshot_id_a, &cache_key("x")).flatten());
+            assert_eq!(None, state_manager.read().unwrap().get_value_inclusive(&snapshot_id_b, &cache_key("z")).flatten());
+        }
+
+        #[test]
+        fn tree_query_lineage_is_cached_until_pruning() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+
+            // a -> b -> c -> d
+            //            \-> e
+            add_block(&state_manager, &db, "a", "b", &[("x", "1"), ("y", "1")]);
+            add_block(&state_manager, &db, "b", "c", &[("x", "2")]);
+            add_block(&state_manager, &db, "c", "e", &[("y", "5")]);
+            let snapshot_ref = state_manager.get_new_ref(&bh("c"), &bh("d")).unwrap();
+            assert_eq!(vec![LineageStep::Snapshot(bh("c")), LineageStep::Snapshot(bh("b"))], state_manager.read().unwrap().lineage(&snapshot_ref.get_id()));
+            let generation = state_manager.read().unwrap().generation();
+
+            assert_eq!(Ok(Some(cache_value("2"))), snapshot_ref.get_value_from_cache_layers(&cache_key("x")).map(|(value, _)| value));
+            assert_eq!(Ok(Some(cache_value("1"))), snapshot_ref.get_value_from_cache_layers(&cache_key("y")).map(|(value, _)| value));
+            assert_eq!(Some(generation), snapshot_ref.lineage.lock().unwrap().as_ref().map(|(g, _)| *g));
+
+            // Adding blocks does not invalidate lineage
+            add_block(&state_manager, &db, "b", "f", &[("x", "6")]);
+            assert_eq!(Ok(Some(cache_value("2"))), snapshot_ref.get_value_from_cache_layers(&cache_key("x")).map(|(value, _)| value));
+            assert_eq!(Some(generation), snapshot_ref.lineage.lock().unwrap().as_ref().map(|(g, _)| *g));
+
+            // Finalizing does
+            state_manager.finalize_snapshot(&bh("b")).unwrap();
+            let generation_after_finalization = state_manager.read().unwrap().generation();
+            assert_ne!(generation, generation_after_finalization);
+            assert_eq!(Ok(Some(cache_value("2"))), snapshot_ref.get_value_from_cache_layers(&cache_key("x")).map(|(value, _)| value));
+            assert_eq!(Ok(Some(cache_value("1"))), snapshot_ref.get_value_from_cache_layers(&cache_key("y")).map(|(value, _)| value));
+            assert_eq!(Some(generation_after_finalization), snapshot_ref.lineage.lock().unwrap().as_ref().map(|(g, _)| *g));
+            assert_eq!(vec![LineageStep::Snapshot(bh("c"))], state_manager.read().unwrap().lineage(&snapshot_ref.get_id()));
+
+            // Compacted chain is a single step
+            add_block(&state_manager, &db, "c", "g", &[("z", "7")]);
+            add_block(&state_manager, &db, "g", "i", &[("w", "8")]);
+            let snapshot_ref_h = state_manager.get_new_ref(&bh("i"), &bh("h")).unwrap();
+            assert_eq!(Ok(Some(cache_value("2"))), snapshot_ref_h.get_value_from_cache_layers(&cache_key("x")).map(|(value, _)| value));
+            state_manager.compact_linear_chains();
+            let lineage_h = vec![LineageStep::Layer(bh("i"), 2), LineageStep::Snapshot(bh("c"))];
+            assert_eq!(lineage_h, state_manager.read().unwrap().lineage(&snapshot_ref_h.get_id()));
+            assert_eq!(Ok(Some(cache_value("7"))), snapshot_ref_h.get_value_from_cache_layers(&cache_key("z")).map(|(value, _)| value));
+            assert_eq!(Ok(Some(cache_value("2"))), snapshot_ref_h.get_value_from_cache_layers(&cache_key("x")).map(|(value, _)| value));
+            assert_eq!(Some(lineage_h), snapshot_ref_h.lineage.lock().unwrap().as_ref().map(|(_, lineage)| lineage.clone()));
+        }
+
+        #[test]
+        fn compacting_linear_chains() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+
+            // a -> b -> c -> d -> e
+            //  \-> f -> g
+            add_block(&state_manager, &db, "a", "b", &[("x", "b"), ("y", "b")]);
+            add_block(&state_manager, &db, "b", "c", &[("x", "c")]);
+            add_block(&state_manager, &db, "c", "d", &[("z", "d")]);
+            add_block(&state_manager, &db, "d", "e", &[("x", "e")]);
+            add_block(&state_manager, &db, "a", "f", &[("x", "f")]);
+            add_block(&state_manager, &db, "f", "g", &[("y", "g")]);
+            assert_eq!(14, state_manager.usage().bytes);
+
+            assert_eq!(2, state_manager.compact_linear_chains());
+            // Copies of x=e, y=b, z=d and x=f, y=g are accounted too
+            assert_eq!(24, state_manager.usage().bytes);
+            assert_eq!(block_hashes(&["e", "d", "c", "b"]), state_manager.read().unwrap().compacted_layers[&bh("e")].blocks);
+            assert_eq!(block_hashes(&["g", "f"]), state_manager.read().unwrap().compacted_layers[&bh("g")].blocks);
+
+            // Reads entering from the newest block and from the middle of the chain
+            assert_eq!(Ok(Some(cache_value("e"))), state_manager.read().unwrap().get_at(&bh("e"), &cache_key("x")));
+            assert_eq!(Ok(Some(cache_value("b"))), state_manager.read().unwrap().get_at(&bh("e"), &cache_key("y")));
+            assert_eq!(Ok(Some(cache_value("d"))), state_manager.read().unwrap().get_at(&bh("e"), &cache_key("z")));
+            assert_eq!(Ok(Some(cache_value("c"))), state_manager.read().unwrap().get_at(&bh("d"), &cache_key("x")));
+            assert_eq!(Ok(None), state_manager.read().unwrap().get_at(&bh("c"), &cache_key("z")));
+            assert_eq!(Ok(Some(cache_value("g"))), state_manager.read().unwrap().get_at(&bh("g"), &cache_key("y")));
+            assert_eq!(Ok(Some(cache_value("f"))), state_manager.read().unwrap().get_at(&bh("g"), &cache_key("x")));
+
+            // Fork in the middle of compacted chain does not see newer blocks
+            add_block(&state_manager, &db, "c", "h", &[("w", "h")]);
+            assert_eq!(Ok(Some(cache_value("c"))), state_manager.read().unwrap().get_at(&bh("h"), &cache_key("x")));
+            assert_eq!(Ok(None), state_manager.read().unwrap().get_at(&bh("h"), &cache_key("z")));
+            let snapshot_ref_h = state_manager.get_new_ref(&bh("h"), &bh("i")).unwrap();
+            let snapshot_ref_e = state_manager.get_new_ref(&bh("e"), &bh("j")).unwrap();
+            // Compacted layer still tells, which block has written the value
+            let source = |block_hash: &str| ValueSource::Snapshot(state_manager.read().unwrap().snapshot_id_of(&bh(block_hash)).unwrap());
+            assert_eq!(Ok((Some(cache_value("c")), source("c"))), snapshot_ref_h.get_value_from_cache_layers(&cache_key("x")));
+            assert_eq!(Ok((None, ValueSource::Storage)), snapshot_ref_h.get_value_from_cache_layers(&cache_key("z")));
+            assert_eq!(Ok((Some(cache_value("e")), source("e"))), snapshot_ref_e.get_value_from_cache_layers(&cache_key("x")));
+            assert_eq!(Ok((Some(cache_value("d")), source("d"))), snapshot_ref_e.get_value_from_cache_layers(&cache_key("z")));
+
+            // Snapshots are still finalized block by block
+            state_manager.finalize_snapshot(&bh("b")).unwrap();
+            {
+                let db = db.lock().unwrap();
+                assert_eq!(Some("b".to_string()), db.get("x"));
+                assert_eq!(None, db.get("z"));
+            }
+            assert!(!state_manager.read().unwrap().compacted_layers.contains_key(&bh("g")));
+            assert_eq!(block_hashes(&["e", "d", "c"]), state_manager.read().unwrap().compacted_layers[&bh("e")].blocks);
+            assert_eq!(Ok(Some(cache_value("e"))), state_manager.read().unwrap().get_at(&bh("e"), &cache_key("x")));
+            assert_eq!(Ok(Some(cache_value("b"))), state_manager.read().unwrap().get_at(&bh("e"), &cache_key("y")));
+            assert_eq!(Ok((Some(cache_value("b")), ValueSource::Storage)), snapshot_ref_e.get_value_from_cache_layers(&cache_key("y")));
+            state_manager.finalize_snapshot(&bh("c")).unwrap();
+            assert_eq!(Ok(Some(cache_value("h"))), state_manager.read().unwrap().get_at(&bh("h"), &cache_key("w")));
+            assert_eq!(Ok((Some(cache_value("e")), source("e"))), snapshot_ref_e.get_value_from_cache_layers(&cache_key("x")));
+            assert_eq!(Ok((Some(cache_value("d")), source("d"))), snapshot_ref_e.get_value_from_cache_layers(&cache_key("z")));
+
+            // Discarding a block drops the whole layer
+            assert_eq!(block_hashes(&["e", "j"]), state_manager.discard_branch(&bh("e")).unwrap());
+            assert!(state_manager.read().unwrap().compacted_layers.is_empty());
+            assert!(state_manager.read().unwrap().compacted_blocks.is_empty());
+            assert_eq!(Ok(Some(cache_value("d"))), state_manager.read().unwrap().get_at(&bh("d"), &cache_key("z")));
+            assert_eq!(Ok(Some(cache_value("c"))), state_manager.read().unwrap().get_at(&bh("d"), &cache_key("x")));
+            // Only snapshots of d and h are left
+            assert_eq!(4, state_manager.usage().bytes);
+        }
+
+        #[test]
+        fn reading_state_at_block() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            db.lock().unwrap().set("w", "0".to_string());
+
+            // a -> b -> c
+            //       \-> d
+            add_block(&state_manager, &db, "a", "b", &[("x", "1"), ("y", "1")]);
+            add_block(&state_manager, &db, "b", "c", &[("x", "2")]);
+            add_block(&state_manager, &db, "b", "d", &[("y", "3")]);
+            let _snapshot_ref = state_manager.get_new_ref(&bh("c"), &bh("e")).unwrap();
+            let pending_blocks = state_manager.read().unwrap().pending_blocks();
+
+            assert_eq!(Ok(Some(cache_value("2"))), state_manager.read().unwrap().get_at(&bh("c"), &cache_key("x")));
+            assert_eq!(Ok(Some(cache_value("1"))), state_manager.read().unwrap().get_at(&bh("c"), &cache_key("y")));
+            assert_eq!(Ok(Some(cache_value("0"))), state_manager.read().unwrap().get_at(&bh("c"), &cache_key("w")));
+            assert_eq!(Ok(Some(cache_value("1"))), state_manager.read().unwrap().get_at(&bh("d"), &cache_key("x")));
+            assert_eq!(Ok(Some(cache_value("3"))), state_manager.read().unwrap().get_at(&bh("d"), &cache_key("y")));
+            assert_eq!(Ok(Some(cache_value("1"))), state_manager.read().unwrap().get_at(&bh("b"), &cache_key("x")));
+            assert_eq!(Ok(None), state_manager.read().unwrap().get_at(&bh("a"), &cache_key("x")));
+            assert_eq!(Ok(Some(cache_value("0"))), state_manager.read().unwrap().get_at(&bh("a"), &cache_key("w")));
+            assert_eq!(Err(BlockStateManagerError::MissingSnapshot(bh("e"))), state_manager.read().unwrap().get_at(&bh("e"), &cache_key("x")));
+            assert_eq!(Err(BlockStateManagerError::UnknownBlock(bh("f"))), state_manager.read().unwrap().get_at(&bh("f"), &cache_key("x")));
+            assert_eq!(pending_blocks, state_manager.read().unwrap().pending_blocks());
+
+            let view_c = state_manager.state_view(&bh("c")).unwrap();
+            let view_d = state_manager.state_view(&bh("d")).unwrap();
+            assert!(state_manager.state_view(&bh("f")).is_err());
+            state_manager.finalize_snapshot(&bh("b")).unwrap();
+
+            assert_eq!(&bh("c"), view_c.block_hash());
+            assert_eq!(Ok(Some(cache_value("2"))), view_c.get(&cache_key("x")));
+            assert_eq!(Ok(Some(cache_value("1"))), view_c.get(&cache_key("y")));
+            assert_eq!(Ok(Some(cache_value("3"))), view_d.get(&cache_key("y")));
+
+            state_manager.finalize_snapshot(&bh("c")).unwrap();
+            assert_eq!(Ok(Some(cache_value("2"))), view_c.get(&cache_key("x")));
+            assert_eq!(Err(BlockStateManagerError::UnknownBlock(bh("d"))), view_d.get(&cache_key("y")));
+        }
+
+        #[test]
+        fn rendering_fork_tree() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+
+            add_block(&state_manager, &db, "a", "b", &[("x", "1"), ("y", "1")]);
+            add_block(&state_manager, &db, "b", "c", &[("x", "2")]);
+            add_block(&state_manager, &db, "a", "d", &[]);
+            let _snapshot_ref = state_manager.get_new_ref(&"b".to_string(), &"e".to_string()).unwrap();
+
+            let expected_ascii = "\
+a (finalized)
+├── b [id=1, 2 writes]
+│   ├── c [id=2, 1 writes]
+│   └── e [id=4, no snapshot]
+└── d [id=3, 0 writes]
+";
+            assert_eq!(expected_ascii, state_manager.read().unwrap().render_ascii());
+
+            let expected_dot = "\
+digraph fork_tree {
+    \"a\" [label=\"a\\nfinalized\"];
+    \"b\" [label=\"b\\nid=1\\n2 writes\"];
+    \"d\" [label=\"d\\nid=3\\n0 writes\"];
+    \"c\" [label=\"c\\nid=2\\n1 writes\"];
+    \"e\" [label=\"e\\nid=4\\nno snapshot\"];
+    \"a\" -> \"b\";
+    \"a\" -> \"d\";
+    \"b\" -> \"c\";
+    \"b\" -> \"e\";
+}
+";
+            assert_eq!(expected_dot, state_manager.read().unwrap().render_dot());
+
+            state_manager.finalize_snapshot(&"b".to_string()).unwrap();
+            let expected_ascii = "\
+b (finalized)
+├── c [id=2, 1 writes]
+└── e [id=4, no snapshot]
+";
+            assert_eq!(expected_ascii, state_manager.read().unwrap().render_ascii());
+        }
+
+        #[test]
+        fn introspecting_desired_chain() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+
+            //       /-> g
+            // a -> b -> c -> d -> e
+            //  \-> e -> f -> h
+            //       \-> k
+            // "a" is already in the storage, and "e" is a single block, so "d -> e" is a duplicate
+            add_block(&state_manager, &db, "a", "b", &[("x", "b")]);
+            add_block(&state_manager, &db, "b", "g", &[("x", "g")]);
+            add_block(&state_manager, &db, "b", "c", &[("x", "c")]);
+            add_block(&state_manager, &db, "c", "d", &[("x", "d")]);
+            add_block(&state_manager, &db, "a", "e", &[("x", "e")]);
+            add_block(&state_manager, &db, "e", "f", &[("x", "f")]);
+            add_block(&state_manager, &db, "f", "h", &[("x", "h")]);
+            let _snapshot_ref_k = state_manager.get_new_ref(&"e".to_string(), &"k".to_string()).unwrap();
+            assert_eq!(
+                Some(BlockStateManagerError::DuplicateBlock("e".to_string())),
+                state_manager.get_new_ref(&"d".to_string(), &"e".to_string()).err(),
+            );
+
+            assert_eq!(block_hashes(&["b", "e", "g", "c", "f", "k", "d", "h"]), state_manager.read().unwrap().pending_blocks());
+            assert_eq!(block_hashes(&["g", "k", "d", "h"]), state_manager.read().unwrap().tips());
+            assert_eq!(&block_hashes(&["b", "e"])[..], state_manager.read().unwrap().children_of(&"a".to_string()));
+            assert_eq!(&block_hashes(&["g", "c"])[..], state_manager.read().unwrap().children_of(&"b".to_string()));
+            assert_eq!(&block_hashes(&["f", "k"])[..], state_manager.read().unwrap().children_of(&"e".to_string()));
+            assert!(state_manager.read().unwrap().children_of(&"h".to_string()).is_empty());
+            assert_eq!(Some(&"f".to_string()), state_manager.read().unwrap().parent_of(&"h".to_string()));
+            assert_eq!(block_hashes(&["c", "b", "a"]), state_manager.read().unwrap().ancestors_of(&"d".to_string()));
+            assert_eq!(block_hashes(&["f", "e", "a"]), state_manager.read().unwrap().ancestors_of(&"h".to_string()));
+            assert_eq!(block_hashes(&["e", "a"]), state_manager.read().unwrap().ancestors_of(&"k".to_string()));
+            assert!(state_manager.read().unwrap().ancestors_of(&"a".to_string()).is_empty());
+            assert_eq!(3, state_manager.read().unwrap().depth());
+            assert!(state_manager.read().unwrap().has_snapshot(&"h".to_string()));
+            assert!(!state_manager.read().unwrap().has_snapshot(&"k".to_string()));
+            assert!(!state_manager.read().unwrap().has_snapshot(&"a".to_string()));
+
+            state_manager.finalize_snapshot(&"e".to_string()).unwrap();
+            assert_eq!(block_hashes(&["f", "k", "h"]), state_manager.read().unwrap().pending_blocks());
+            assert_eq!(block_hashes(&["k", "h"]), state_manager.read().unwrap().tips());
+            assert_eq!(2, state_manager.read().unwrap().depth());
+            assert_eq!(block_hashes(&["f", "e"]), state_manager.read().unwrap().ancestors_of(&"h".to_string()));
+        }
+
+        #[test]
+        fn finalizing_out_of_order() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            let genesis_block = "genesis".to_string();
+            let block_a = "a".to_string();
+            let block_b = "b".to_string();
+            let block_c = "c".to_string();
+            let x = cache_key("x");
+            let y = cache_key("y");
+
+            let snapshot_ref = state_manager.get_new_ref(&genesis_block, &block_a).unwrap();
+            let snapshot = write_values(db.clone(), snapshot_ref, &[("x", "1"), ("y", "1")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+            let snapshot_ref = state_manager.get_new_ref(&block_a, &block_b).unwrap();
+            let snapshot = write_values(db.clone(), snapshot_ref, &[("x", "2")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+            let snapshot_ref_c = state_manager.get_new_ref(&block_b, &block_c).unwrap();
+
+            // B cannot jump ahead of A
+            assert_eq!(Err(BlockStateManagerError::NonRootFinalization(block_b.clone())), state_manager.finalize_snapshot(&block_b));
+            assert!(db.lock().unwrap().data.is_empty());
+            assert_eq!(Some(cache_value("2")), state_manager.read().unwrap().get_value_recursively(&snapshot_ref_c.get_id(), &x).flatten());
+
+            // But it can bring A along
+            assert_eq!(vec![block_a, block_b], state_manager.finalize_up_to(&"b".to_string()).unwrap());
+            {
+                let db = db.lock().unwrap();
+                assert_eq!(Some("2".to_string()), db.get("x"));
+                assert_eq!(Some("1".to_string()), db.get("y"));
+            }
+            assert_eq!(None, state_manager.read().unwrap().get_value_recursively(&snapshot_ref_c.get_id(), &x).flatten());
+            // Committed values are served by the storage now
+            assert_eq!(Ok((Some(cache_value("2")), ValueSource::Storage)), snapshot_ref_c.get_value_from_cache_layers(&x));
+            assert_eq!(Ok((Some(cache_value("1")), ValueSource::Storage)), snapshot_ref_c.get_value_from_cache_layers(&y));
+        }
+
+
+        #[test]
+        fn finalizing_chain_at_once() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            let genesis_block = "genesis".to_string();
+            let block_a = "a".to_string();
+            let block_b = "b".to_string();
+            let block_c = "c".to_string();
+            let block_d = "d".to_string();
+            let block_e = "e".to_string();
+            let block_f = "f".to_string();
+
+            // genesis -> a -> b -> c -> d
+            //             \-> e  \-> f
+            let snapshot_ref = state_manager.get_new_ref(&genesis_block, &block_a).unwrap();
+            let snapshot = write_values(db.clone(), snapshot_ref, &[("x", "1"), ("y", "1")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+            let snapshot_ref = state_manager.get_new_ref(&block_a, &block_b).unwrap();
+            let snapshot = write_values(db.clone(), snapshot_ref, &[("x", "2")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+            let snapshot_ref = state_manager.get_new_ref(&block_a, &block_e).unwrap();
+            let snapshot = write_values(db.clone(), snapshot_ref, &[("e", "e")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+            let snapshot_ref = state_manager.get_new_ref(&block_b, &block_c).unwrap();
+            let snapshot = write_values(db.clone(), snapshot_ref, &[("x", "3"), ("z", "3")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+            let snapshot_ref = state_manager.get_new_ref(&block_b, &block_f).unwrap();
+            let snapshot = write_values(db.clone(), snapshot_ref, &[("f", "f")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+            let snapshot_ref = state_manager.get_new_ref(&block_c, &block_d).unwrap();
+            let snapshot = write_values(db.clone(), snapshot_ref, &[("x", "4")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+
+            let finalized = state_manager.finalize_up_to(&block_c).unwrap();
+            assert_eq!(vec![block_a.clone(), block_b.clone(), block_c.clone()], finalized);
+            {
+                let db = db.lock().unwrap();
+                assert_eq!(Some("3".to_string()), db.get("x"));
+                assert_eq!(Some("1".to_string()), db.get("y"));
+                assert_eq!(Some("3".to_string()), db.get("z"));
+                assert_eq!(None, db.get("e"));
+                assert_eq!(None, db.get("f"));
+            }
+            assert_eq!(Some(BlockStateManagerError::UnknownBlock(block_e.clone())), state_manager.finalize_snapshot(&block_e).err());
+            assert_eq!(Some(BlockStateManagerError::UnknownBlock(block_f.clone())), state_manager.finalize_snapshot(&block_f).err());
+            assert_eq!(Some(BlockStateManagerError::AlreadyFinalized(block_c.clone())), state_manager.finalize_up_to(&block_c).err());
+
+            let finalized = state_manager.finalize_up_to(&block_d).unwrap();
+            assert_eq!(vec![block_d], finalized);
+            assert!(state_manager.read().unwrap().is_empty());
+            assert_eq!(Some("4".to_string()), db.lock().unwrap().get("x"));
+        }
+
+        #[test]
+        fn finalizing_chain_with_missing_snapshot() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            let genesis_block = "genesis".to_string();
+            let block_a = "a".to_string();
+            let block_b = "b".to_string();
+
+            let snapshot_ref = state_manager.get_new_ref(&genesis_block, &block_a).unwrap();
+            let snapshot = write_values(db.clone(), snapshot_ref, &[("x", "1")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+            let _snapshot_ref = state_manager.get_new_ref(&block_a, &block_b).unwrap();
+
+            assert_eq!(Some(BlockStateManagerError::MissingSnapshot(block_b.clone())), state_manager.finalize_up_to(&block_b).err());
+            assert!(db.lock().unwrap().data.is_empty());
+            assert_eq!(vec![block_a], state_manager.finalize_up_to(&"a".to_string()).unwrap());
+            assert_eq!(Some("1".to_string()), db.lock().unwrap().get("x"));
+        }
+
+
+        #[test]
+        fn discarding_branch() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            let genesis_block = "genesis".to_string();
+            let block_a = "a".to_string();
+            let block_b = "b".to_string();
+            let block_c = "c".to_string();
+            let block_d = "d".to_string();
+            let block_e = "e".to_string();
+
+            // genesis -> a -> b -> c
+            //             \-> d -> e
+            let snapshot_ref = state_manager.get_new_ref(&genesis_block, &block_a).unwrap();
+            let snapshot = write_values(db.clone(), snapshot_ref, &[("x", "1")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+            let snapshot_ref = state_manager.get_new_ref(&block_a, &block_b).unwrap();
+            let snapshot = write_values(db.clone(), snapshot_ref, &[("x", "2")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+            let snapshot_ref = state_manager.get_new_ref(&block_b, &block_c).unwrap();
+            let snapshot = write_values(db.clone(), snapshot_ref, &[("x", "3")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+            let snapshot_ref = state_manager.get_new_ref(&block_a, &block_d).unwrap();
+            let snapshot = write_values(db.clone(), snapshot_ref, &[("x", "4")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+            // E is still being executed
+            let _snapshot_ref = state_manager.get_new_ref(&block_d, &block_e).unwrap();
+
+            let discarded = state_manager.discard_branch(&block_d).unwrap();
+            assert_eq!(vec![block_d.clone(), block_e.clone()], discarded);
+            assert_eq!(Some(BlockStateManagerError::UnknownBlock(block_d.clone())), state_manager.discard_branch(&block_d).err());
+            assert_eq!(Some(BlockStateManagerError::UnknownBlock(block_e.clone())), state_manager.finalize_snapshot(&block_e).err());
+            assert_eq!(Some(BlockStateManagerError::AlreadyFinalized(genesis_block.clone())), state_manager.discard_branch(&genesis_block).err());
+
+            let discarded = state_manager.discard_branch(&block_b).unwrap();
+            assert_eq!(vec![block_b.clone(), block_c.clone()], discarded);
+
+            state_manager.finalize_snapshot(&block_a).unwrap();
+            assert!(state_manager.read().unwrap().is_empty());
+            {
+                let db = db.lock().unwrap();
+                assert_eq!(Some("1".to_string()), db.get("x"));
+            }
+        }
+
+        #[test]
+        fn discarding_whole_tree() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            let genesis_block = "genesis".to_string();
+            let block_a = "a".to_string();
+            let block_b = "b".to_string();
+
+            let snapshot_ref = state_manager.get_new_ref(&genesis_block, &block_a).unwrap();
+            let snapshot = write_values(db.clone(), snapshot_ref, &[("x", "1")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+            let snapshot_ref = state_manager.get_new_ref(&block_a, &block_b).unwrap();
+            let snapshot = write_values(db.clone(), snapshot_ref, &[("x", "2")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+
+            let discarded = state_manager.discard_branch(&block_a).unwrap();
+            assert_eq!(vec![block_a, block_b], discarded);
+            assert!(state_manager.read().unwrap().is_empty());
+            assert!(db.lock().unwrap().data.is_empty());
+        }
+
+
+        #[test]
+        fn adding_alien_snapshot() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            let genesis_block = "genesis".to_string();
+            let block_a = "a".to_string();
+
+            let snapshot_ref = state_manager.get_new_ref(&genesis_block, &block_a).unwrap();
+            let other_db = DB::default();
+            let other_state_manager = BlockStateManagerHandle::new(other_db.clone());
+            other_state_manager.get_new_ref(&genesis_block, &block_a).unwrap();
+            let alien_snapshot_ref = other_state_manager.get_new_ref(&genesis_block, &"b".to_string()).unwrap();
+            let alien_snapshot = write_values(other_db, alien_snapshot_ref, &[("x", "1")]);
+            let alien_snapshot_id = alien_snapshot.get_id();
+
+            assert_eq!(Err(BlockStateManagerError::AlienSnapshot(alien_snapshot_id)), state_manager.add_snapshot(alien_snapshot));
+
+            let snapshot = write_values(db.clone(), snapshot_ref, &[("x", "2")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+        }
+
+        #[test]
+        fn adding_snapshot_of_dropped_manager() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::<Database, FrozenSnapshot, BlockHash>::new(db.clone());
+            let snapshot_ref = state_manager.get_new_ref(&bh("genesis"), &bh("a")).unwrap();
+
+            // Same ids as this manager issues, but token of the other manager is dead
+            let other_db = DB::default();
+            let other_state_manager = BlockStateManagerHandle::<Database, FrozenSnapshot, BlockHash>::new(other_db.clone());
+            let alien_snapshot_ref = other_state_manager.get_new_ref(&bh("genesis"), &bh("a")).unwrap();
+            let alien_snapshot = write_values(other_db, alien_snapshot_ref, &[("x", "1")]);
+            drop(other_state_manager);
+            assert!(alien_snapshot.branch_token().is_dead());
+            let alien_snapshot_id = alien_snapshot.get_id();
+            assert_eq!(snapshot_ref.get_id(), alien_snapshot_id);
+
+            assert_eq!(Err(BlockStateManagerError::AlienSnapshot(alien_snapshot_id)), state_manager.add_snapshot(alien_snapshot));
+
+            let snapshot = write_values(db.clone(), snapshot_ref, &[("x", "2")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+        }
+
+        #[test]
+        fn adding_snapshot_twice() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            let genesis_block = "genesis".to_string();
+            let block_a = "a".to_string();
+
+            let snapshot_ref = state_manager.get_new_ref(&genesis_block, &block_a).unwrap();
+            let snapshot_id = snapshot_ref.get_id();
+            let snapshot = write_values(db.clone(), snapshot_ref, &[("x", "1")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+
+            let same_snapshot_ref = TreeQuery::new(snapshot_id, db.clone(), state_manager.read_only(), state_manager.read().unwrap().branch_token(&block_a));
+            let duplicate = write_values(db.clone(), same_snapshot_ref, &[("x", "2")]);
+            assert_eq!(Err(BlockStateManagerError::DuplicateBlock(block_a)), state_manager.add_snapshot(duplicate));
+        }
+
+        #[test]
+        fn finalizing_alien_block() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::<Database, FrozenSnapshot, BlockHash>::new(db.clone());
+            let genesis_block = "genesis".to_string();
+            let block_a = "a".to_string();
+            let block_b = "b".to_string();
+
+            assert_eq!(Err(BlockStateManagerError::UnknownBlock(block_a.clone())), state_manager.finalize_snapshot(&block_a));
+
+            let snapshot_ref = state_manager.get_new_ref(&genesis_block, &block_a).unwrap();
+            let snapshot = write_values(db.clone(), snapshot_ref, &[("x", "1")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+
+            assert_eq!(Err(BlockStateManagerError::UnknownBlock(block_b.clone())), state_manager.finalize_snapshot(&block_b));
+            assert_eq!(Err(BlockStateManagerError::AlreadyFinalized(genesis_block.clone())), state_manager.finalize_snapshot(&genesis_block));
+            assert!(db.lock().unwrap().data.is_empty());
+        }
+
+        #[test]
+        fn finalizing_block_without_snapshot() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::<Database, FrozenSnapshot, BlockHash>::new(db.clone());
+            let genesis_block = "genesis".to_string();
+            let block_a = "a".to_string();
+
+            let _snapshot_ref = state_manager.get_new_ref(&genesis_block, &block_a).unwrap();
+            assert_eq!(Err(BlockStateManagerError::MissingSnapshot(block_a.clone())), state_manager.finalize_snapshot(&block_a));
+            assert!(!state_manager.read().unwrap().is_empty());
+        }
+
+        #[test]
+        fn finalizing_non_root_block() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            let genesis_block = "genesis".to_string();
+            let block_a = "a".to_string();
+            let block_b = "b".to_string();
+
+            let snapshot_ref = state_manager.get_new_ref(&genesis_block, &block_a).unwrap();
+            let snapshot = write_values(db.clone(), snapshot_ref, &[("x", "1")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+            let snapshot_ref = state_manager.get_new_ref(&block_a, &block_b).unwrap();
+            let snapshot = write_values(db.clone(), snapshot_ref, &[("x", "2")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+
+            assert_eq!(Err(BlockStateManagerError::NonRootFinalization(block_b.clone())), state_manager.finalize_snapshot(&block_b));
+            assert!(db.lock().unwrap().data.is_empty());
+
+            state_manager.finalize_snapshot(&block_a).unwrap();
+            state_manager.finalize_snapshot(&block_b).unwrap();
+            assert_eq!(Some("2".to_string()), db.lock().unwrap().get("x"));
+            assert!(state_manager.read().unwrap().is_empty());
+        }
+
+        #[test]
+        fn finalizing_same_block_hash_twice() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            let genesis_block = "genesis".to_string();
+            let block_a = "a".to_string();
+            let block_b = "b".to_string();
+
+            let snapshot_ref = state_manager.get_new_ref(&genesis_block, &block_a).unwrap();
+            let snapshot = write_values(db.clone(), snapshot_ref, &[("x", "1")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+            let snapshot_ref = state_manager.get_new_ref(&block_a, &block_b).unwrap();
+            let snapshot = write_values(db.clone(), snapshot_ref, &[("x", "2")]);
+            state_manager.add_snapshot(snapshot).unwrap();
+
+            state_manager.finalize_snapshot(&block_a).unwrap();
+            assert_eq!(Err(BlockStateManagerError::AlreadyFinalized(block_a.clone())), state_manager.finalize_snapshot(&block_a));
+            state_manager.finalize_snapshot(&block_b).unwrap();
+            assert_eq!(Err(BlockStateManagerError::AlreadyFinalized(block_b.clone())), state_manager.finalize_snapshot(&block_b));
+            assert_eq!(Some("2".to_string()), db.lock().unwrap().get("x"));
+        }
+
+        #[test]
+        fn resending_finalized_blocks() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            add_block(&state_manager, &db, "genesis", "a", &[("x", "1")]);
+            add_block(&state_manager, &db, "a", "b", &[("x", "2")]);
+            add_block(&state_manager, &db, "b", "c", &[("x", "3")]);
+            state_manager.finalize_snapshot(&bh("a")).unwrap();
+            state_manager.finalize_snapshot(&bh("b")).unwrap();
+
+            // Re-executed block would overwrite values committed after it
+            assert_eq!(Some(BlockStateManagerError::DuplicateBlock(bh("a"))), state_manager.get_new_ref(&bh("b"), &bh("a")).err());
+            assert_eq!(Some(BlockStateManagerError::DuplicateBlock(bh("a"))), state_manager.get_new_ref(&bh("genesis"), &bh("a")).err());
+            assert_eq!(Err(BlockStateManagerError::AlreadyFinalized(bh("a"))), state_manager.finalize_snapshot(&bh("a")));
+            assert_eq!(Some(BlockStateManagerError::AlreadyFinalized(bh("a"))), state_manager.finalize_up_to(&bh("a")).err());
+            assert_eq!(Some(BlockStateManagerError::AlreadyFinalized(bh("a"))), state_manager.discard_branch(&bh("a")).err());
+            assert_eq!(Some("2".to_string()), db.lock().unwrap().get("x"));
+            assert_eq!(block_hashes(&["c"]), state_manager.read().unwrap().pending_blocks());
+        }
+
+        #[test]
+        fn requesting_ref_with_itself_as_parent() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::<Database, FrozenSnapshot, BlockHash>::new(db.clone());
+
+            let result = state_manager.get_new_ref(&bh("a"), &bh("a"));
+            assert_eq!(Some(BlockStateManagerError::UnknownParent(bh("a"))), result.err());
+            assert!(state_manager.read().unwrap().is_empty());
+            assert_eq!(Some(BlockStateManagerError::UnknownBlock(bh("a"))), state_manager.finalize_up_to(&bh("a")).err());
+        }
+
+        #[test]
+        fn requesting_ref_from_same_block_twice() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::<Database, FrozenSnapshot, BlockHash>::new(db.clone());
+            let genesis_block = "genesis".to_string();
+            let block_a = "a".to_string();
+            let block_b = "b".to_string();
+
+            let _snapshot_ref = state_manager.get_new_ref(&genesis_block, &block_a).unwrap();
+            let result = state_manager.get_new_ref(&genesis_block, &block_a);
+            assert_eq!(Some(BlockStateManagerError::DuplicateBlock(block_a.clone())), result.err());
+            // Same block hash with different parent is also a duplicate
+            let result = state_manager.get_new_ref(&block_b, &block_a);
+            assert_eq!(Some(BlockStateManagerError::DuplicateBlock(block_a.clone())), result.err());
+        }
+
+        #[test]
+        fn requesting_ref_from_unknown_parent() {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::<Database, FrozenSnapshot, BlockHash>::new(db.clone());
+            let genesis_block = "genesis".to_string();
+            let block_a = "a".to_string();
+            let block_b = "b".to_string();
+            let block_c = "c".to_string();
+
+            let snapshot_ref = state_manager.get_new_ref(&genesis_block, &block_a).unwrap();
+            // Block A has no snapshot yet
+            let result = state_manager.get_new_ref(&block_a, &block_b);
+            assert_eq!(Some(BlockStateManagerError::UnknownParent(block_a.clone())), result.err());
+            let result = state_manager.get_new_ref(&block_c, &block_b);
+            assert_eq!(Some(BlockStateManagerError::UnknownParent(block_c.clone())), result.err());
+
+            // Once everything pending is finalized, new blocks have to build on the last finalized one
+            state_manager.add_snapshot(write_values(db.clone(), snapshot_ref, &[("x", "1")])).unwrap();
+            state_manager.finalize_snapshot(&block_a).unwrap();
+            assert!(state_manager.read().unwrap().is_empty());
+            let result = state_manager.get_new_ref(&block_c, &block_b);
+            assert_eq!(Some(BlockStateManagerError::UnknownParent(block_c.clone())), result.err());
+            assert!(state_manager.get_new_ref(&block_a, &block_b).is_ok());
+        }
+    }
+
+    mod benchmarks {
+        use std::time::{Duration, Instant};
+        use super::*;
+
+        const DEPTH: usize = 64;
+        const KEYS_PER_BLOCK: usize = 32;
+        const READS: usize = 2_000;
+
+        /// Builds linear chain of `DEPTH` blocks, each writing its own keys,
+        /// and measures reads of keys from the oldest block and keys that are only in the database.
+        fn measure_deep_fork_reads(with_filter: bool) -> (Duration, Duration) {
+            let db = DB::default();
+            let state_manager = BlockStateManagerHandle::new(db.clone());
+            for key_idx in 0..KEYS_PER_BLOCK {
+                db.lock().unwrap().set(&format!("db_{}", key_idx), "db".to_string());
+            }
+            for block_idx in 0..DEPTH {
+                let prev_block_hash = block_idx.to_string();
+                let block_hash = (block_idx + 1).to_string();
+                let snapshot_ref = state_manager.get_new_ref(&prev_block_hash, &block_hash).unwrap();
+                let keys: Vec<String> = (0..KEYS_PER_BLOCK).map(|key_idx| format!("key_{}_{}", block_idx, key_idx)).collect();
+                let values: Vec<(&str, &str)> = keys.iter().map(|key| (key.as_str(), "value")).collect();
+                let snapshot = write_values(db.clone(), snapshot_ref, &values);
+                let snapshot = if with_filter { snapshot } else { snapshot.without_filter() };
+                state_manager.add_snapshot(snapshot).unwrap();
+            }
+            let snapshot_ref = state_manager.get_new_ref(&DEPTH.to_string(), &"tip".to_string()).unwrap();
+
+            let oldest_keys: Vec<CacheKey> = (0..KEYS_PER_BLOCK).map(|key_idx| cache_key(&format!("key_0_{}", key_idx))).collect();
+            let db_keys: Vec<CacheKey> = (0..KEYS_PER_BLOCK).map(|key_idx| cache_key(&format!("db_{}", key_idx))).collect();
+
+            let measure = |keys: &[CacheKey]| {
+                let start = Instant::now();
+                for read_idx in 0..READS {
+                    let (value, _) = snapshot_ref.get_value_from_cache_layers(&keys[read_idx % keys.len()]).unwrap();
+                    assert!(value.is_some());
+                }
+                start.elapsed()
+            };
+            (measure(&oldest_keys), measure(&db_keys))
+        }
+
+        /// Run with `cargo test --release -- --ignored --nocapture deep_fork_reads`
+        #[test]
+        #[ignore = "benchmark"]
+        fn deep_fork_reads() {
+            let (oldest_unfiltered, db_unfiltered) = measure_deep_fork_reads(false);
+            let (oldest_filtered, db_filtered) = measure_deep_fork_reads(true);
+            println!("Depth {}, {} reads", DEPTH, READS);
+            println!("Reads from the oldest snapshot: without filter {:?}, with filter {:?}", oldest_unfiltered, oldest_filtered);
+            println!("Reads from the database:        without filter {:?}, with filter {:?}", db_unfiltered, db_filtered);
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::hash::{Hash, Hasher};
+
+// ~1% false positive rate
+const BITS_PER_KEY: usize = 10;
+const HASHES_COUNT: u64 = 7;
+
+/// Compact probabilistic set of keys.
+/// It can tell for sure that key is not in the set, but can give false positive.
+#[derive(Debug, Clone)]
+pub struct KeyFilter {
+    bits: Vec<u64>,
+}
+
+impl KeyFilter {
+    pub fn from_keys<'a, K, I>(keys: I) -> Self
+        where
+            K: Hash + 'a,
+            I: ExactSizeIterator<Item=&'a K>,
+    {
+        // Power of two, so bit position is computed with mask instead of division
+        let words = (keys.len() * BITS_PER_KEY).div_ceil(64).max(1).next_power_of_two();
+        let mut filter = Self {
+            bits: vec![0; words],
+        };
+        for key in keys {
+            for bit in filter.bit_positions(key) {
+                filter.bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        filter
+    }
+
+    /// `false` means that key has definitely not been added to the filter.
+    pub fn may_contain<K: Hash>(&self, key: &K) -> bool {
+        self.bit_positions(key)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Double hashing: single 64 bit hash is split into 2 halves, which produce all positions.
+    /// Step is odd, so it is coprime with the power of two number of bits and positions never repeat.
+    fn bit_positions<K: Hash>(&self, key: &K) -> impl Iterator<Item=usize> {
+        let mut hasher = FnvHasher::default();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        let h1 = hash & 0xffff_ffff;
+        let h2 = (hash >> 32) | 1;
+        let mask = (self.bits.len() * 64 - 1) as u64;
+        (0..HASHES_COUNT).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) & mask) as usize)
+    }
+}
+
+/// FNV-1a, much cheaper than default SipHash for short keys, and collision resistance is not needed here.
+struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_false_negatives() {
+        let keys: Vec<String> = (0..1000).map(|i| format!("key_{}", i)).collect();
+        let filter = KeyFilter::from_keys(keys.iter());
+        for key in &keys {
+            assert!(filter.may_contain(key));
+        }
+    }
+
+    #[test]
+    fn mostly_rejects_absent_keys() {
+        let keys: Vec<String> = (0..1000).map(|i| format!("key_{}", i)).collect();
+        let filter = KeyFilter::from_keys(keys.iter());
+        let false_positives = (0..1000)
+            .map(|i| format!("other_{}", i))
+            .filter(|key| filter.may_contain(key))
+            .count();
+        assert!(false_positives < 50, "too many false positives: {}", false_positives);
+
+        let empty = KeyFilter::from_keys(Vec::<String>::new().iter());
+        assert!(!empty.may_contain(&"key_1".to_string()));
+    }
+
+    #[test]
+    fn each_key_sets_distinct_bits() {
+        let keys: Vec<String> = (0..1000).map(|i| format!("key_{}", i)).collect();
+        let filter = KeyFilter::from_keys(keys.iter());
+        let empty = KeyFilter::from_keys(Vec::<String>::new().iter());
+        for key in keys.iter().chain([String::new(), "0".to_string()].iter()) {
+            for filter in [&filter, &empty] {
+                let positions: std::collections::HashSet<_> = filter.bit_positions(key).collect();
+                assert_eq!(HASHES_COUNT as usize, positions.len(), "positions of {:?} collapse", key);
+            }
+        }
+    }
+}
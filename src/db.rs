@@ -2,7 +2,10 @@
 #![allow(unused_variables)]
 
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
 use sov_first_read_last_write_cache::cache::CacheLog;
+use crate::metrics::Metrics;
 
 #[derive(Default, Debug)]
 pub struct Database {
@@ -34,4 +37,53 @@ pub trait Storage {
 
     fn commit(&mut self, data: Self::Payload);
     fn get(&self, key: &Self::Key) -> Option<Self::Value>;
-}
\ No newline at end of file
+}
+
+/// Payload, which can tell how many keys its commit writes.
+pub trait CommitSize {
+    fn keys_written(&self) -> usize;
+}
+
+impl CommitSize for CacheLog {
+    fn keys_written(&self) -> usize {
+        self.len()
+    }
+}
+
+/// [`Storage`], which reports each commit to [`Metrics`], no matter who commits.
+#[derive(Debug)]
+pub struct MeteredStorage<P> {
+    inner: P,
+    metrics: Arc<dyn Metrics>,
+}
+
+impl<P> MeteredStorage<P> {
+    pub fn new(inner: P, metrics: Arc<dyn Metrics>) -> Self {
+        Self { inner, metrics }
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+}
+
+impl<P> Storage for MeteredStorage<P>
+    where
+        P: Storage,
+        P::Payload: CommitSize,
+{
+    type Key = P::Key;
+    type Value = P::Value;
+    type Payload = P::Payload;
+
+    fn commit(&mut self, data: Self::Payload) {
+        let keys_written = data.keys_written();
+        let started = Instant::now();
+        self.inner.commit(data);
+        self.metrics.storage_commit(keys_written, started.elapsed());
+    }
+
+    fn get(&self, key: &Self::Key) -> Option<Self::Value> {
+        self.inner.get(key)
+    }
+}
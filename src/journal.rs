@@ -0,0 +1,323 @@
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use crate::block_state_manager::Snapshot;
+
+const HEADER_TAG: u8 = 0;
+const SNAPSHOT_ADDED_TAG: u8 = 1;
+const FINALIZED_TAG: u8 = 2;
+const DISCARDED_TAG: u8 = 3;
+
+/// Binary encoding of values, that go to the journal.
+pub trait JournalCodec: Sized {
+    fn encode(&self, out: &mut Vec<u8>);
+
+    /// Consumes decoded bytes from the beginning of the input.
+    fn decode(input: &mut &[u8]) -> io::Result<Self>;
+}
+
+impl JournalCodec for u64 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn decode(input: &mut &[u8]) -> io::Result<Self> {
+        let bytes = take(input, 8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().expect("exactly 8 bytes have been taken")))
+    }
+}
+
+impl JournalCodec for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_bytes(self.as_bytes(), out);
+    }
+
+    fn decode(input: &mut &[u8]) -> io::Result<Self> {
+        String::from_utf8(decode_bytes(input)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl JournalCodec for Vec<u8> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_bytes(self, out);
+    }
+
+    fn decode(input: &mut &[u8]) -> io::Result<Self> {
+        decode_bytes(input)
+    }
+}
+
+impl<T: JournalCodec> JournalCodec for Option<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_option_ref(self.as_ref(), out);
+    }
+
+    fn decode(input: &mut &[u8]) -> io::Result<Self> {
+        match take(input, 1)?[0] {
+            0 => Ok(None),
+            1 => Ok(Some(T::decode(input)?)),
+            tag => Err(invalid_data(format!("unexpected option tag {}", tag))),
+        }
+    }
+}
+
+/// Length prefixed bytes.
+pub fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    (bytes.len() as u64).encode(out);
+    out.extend_from_slice(bytes);
+}
+
+pub fn decode_bytes(input: &mut &[u8]) -> io::Result<Vec<u8>> {
+    let len = u64::decode(input)? as usize;
+    Ok(take(input, len)?.to_vec())
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if input.len() < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    let (taken, rest) = input.split_at(len);
+    *input = rest;
+    Ok(taken)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Log of the pending fork tree, which allows [`crate::block_state_manager::BlockStateManager`]
+/// to be rebuilt after restart without re-executing pending blocks.
+pub trait Journal<Bh, S: Snapshot>: Debug + Send + Sync {
+    /// Snapshot has been added for the block, on top of given parent.
+    fn snapshot_added(&mut self, parent_block_hash: &Bh, block_hash: &Bh, snapshot: &S, latest_snapshot_id: Option<&S::Id>) -> io::Result<()>;
+
+    /// Blocks have been committed to the storage in given order, and their competing forks have been pruned.
+    fn blocks_finalized(&mut self, block_hashes: &[Bh], latest_snapshot_id: Option<&S::Id>) -> io::Result<()>;
+
+    /// Block has been removed together with all its descendants.
+    fn branch_discarded(&mut self, block_hash: &Bh, latest_snapshot_id: Option<&S::Id>) -> io::Result<()>;
+
+    /// Replaces whole journal with the current pending tree, dropping records of blocks, which have left it.
+    /// Blocks are `(parent, block, snapshot)`, and each parent goes before its children.
+    fn rewrite(&mut self, last_finalized_block: Option<&Bh>, latest_snapshot_id: Option<&S::Id>, blocks: &[(&Bh, &Bh, &S)]) -> io::Result<()>;
+}
+
+/// Entry of the journal, as it is read back.
+pub enum JournalRecord<Bh, S: Snapshot> {
+    /// Written at the beginning of the journal, each time it is rewritten.
+    Header {
+        last_finalized_block: Option<Bh>,
+        latest_snapshot_id: Option<S::Id>,
+    },
+    SnapshotAdded {
+        parent_block_hash: Bh,
+        block_hash: Bh,
+        snapshot: S,
+        latest_snapshot_id: Option<S::Id>,
+    },
+    /// Snapshot of the block is in the storage, so it should be replayed without committing it again.
+    Finalized {
+        block_hash: Bh,
+        latest_snapshot_id: Option<S::Id>,
+    },
+    Discarded {
+        block_hash: Bh,
+        latest_snapshot_id: Option<S::Id>,
+    },
+}
+
+/// Journal in a local file. Each record is prefixed with its length,
+/// so record torn by crash in the middle of the write is detected and dropped.
+#[derive(Debug)]
+pub struct FileJournal {
+    path: PathBuf,
+    file: File,
+}
+
+impl FileJournal {
+    /// Opens journal for appending, creating it if it does not exist.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path,
+            file,
+        })
+    }
+
+    /// Reads all complete records. Missing journal is the same as empty one.
+    pub fn read<Bh, S>(path: impl AsRef<Path>) -> io::Result<Vec<JournalRecord<Bh, S>>>
+        where
+            Bh: JournalCodec,
+            S: Snapshot + JournalCodec,
+            S::Id: JournalCodec,
+    {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut input = &bytes[..];
+        let mut records = Vec::new();
+        while let Ok(len) = u64::decode(&mut input) {
+            // Incomplete record is the last one, which has not been fully written
+            let Ok(mut record) = take(&mut input, len as usize) else {
+                break;
+            };
+            records.push(decode_record(&mut record)?);
+        }
+        Ok(records)
+    }
+
+    /// Records are written at once, so they are synced together.
+    fn append(&mut self, records: &[Vec<u8>]) -> io::Result<()> {
+        let content: Vec<u8> = records.iter().flat_map(|record| frame(record)).collect();
+        self.file.write_all(&content)?;
+        self.file.sync_data()
+    }
+}
+
+impl<Bh, S> Journal<Bh, S> for FileJournal
+    where
+        Bh: JournalCodec,
+        S: Snapshot + JournalCodec,
+        S::Id: JournalCodec,
+{
+    fn snapshot_added(&mut self, parent_block_hash: &Bh, block_hash: &Bh, snapshot: &S, latest_snapshot_id: Option<&S::Id>) -> io::Result<()> {
+        let mut record = Vec::new();
+        encode_snapshot_added(parent_block_hash, block_hash, snapshot, latest_snapshot_id, &mut record);
+        self.append(&[record])
+    }
+
+    fn blocks_finalized(&mut self, block_hashes: &[Bh], latest_snapshot_id: Option<&S::Id>) -> io::Result<()> {
+        let records: Vec<_> = block_hashes.iter()
+            .map(|block_hash| encode_block_removed(FINALIZED_TAG, block_hash, latest_snapshot_id))
+            .collect();
+        self.append(&records)
+    }
+
+    fn branch_discarded(&mut self, block_hash: &Bh, latest_snapshot_id: Option<&S::Id>) -> io::Result<()> {
+        self.append(&[encode_block_removed(DISCARDED_TAG, block_hash, latest_snapshot_id)])
+    }
+
+    fn rewrite(&mut self, last_finalized_block: Option<&Bh>, latest_snapshot_id: Option<&S::Id>, blocks: &[(&Bh, &Bh, &S)]) -> io::Result<()> {
+        let mut header = vec![HEADER_TAG];
+        encode_option_ref(last_finalized_block, &mut header);
+        encode_option_ref(latest_snapshot_id, &mut header);
+        let mut content = frame(&header);
+        for (parent_block_hash, block_hash, snapshot) in blocks {
+            let mut record = Vec::new();
+            encode_snapshot_added(*parent_block_hash, *block_hash, *snapshot, latest_snapshot_id, &mut record);
+            content.extend(frame(&record));
+        }
+
+        // Written aside and renamed, so crash in the middle leaves previous journal intact
+        let mut tmp_path = OsString::from(self.path.as_os_str());
+        tmp_path.push(".tmp");
+        {
+            let mut tmp_file = File::create(&tmp_path)?;
+            tmp_file.write_all(&content)?;
+            tmp_file.sync_data()?;
+        }
+        std::fs::rename(&tmp_path, &self.path)?;
+        // Rename itself is durable only after directory is synced
+        let directory = match self.path.parent() {
+            Some(directory) if !directory.as_os_str().is_empty() => directory,
+            _ => Path::new("."),
+        };
+        File::open(directory)?.sync_all()?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+fn frame(record: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(record.len() + 8);
+    encode_bytes(record, &mut framed);
+    framed
+}
+
+fn encode_option_ref<T: JournalCodec>(value: Option<&T>, out: &mut Vec<u8>) {
+    match value {
+        None => out.push(0),
+        Some(value) => {
+            out.push(1);
+            value.encode(out);
+        }
+    }
+}
+
+fn encode_snapshot_added<Bh, S>(parent_block_hash: &Bh, block_hash: &Bh, snapshot: &S, latest_snapshot_id: Option<&S::Id>, out: &mut Vec<u8>)
+    where
+        Bh: JournalCodec,
+        S: Snapshot + JournalCodec,
+        S::Id: JournalCodec,
+{
+    out.push(SNAPSHOT_ADDED_TAG);
+    parent_block_hash.encode(out);
+    block_hash.encode(out);
+    snapshot.encode(out);
+    encode_option_ref(latest_snapshot_id, out);
+}
+
+fn encode_block_removed<Bh: JournalCodec, Id: JournalCodec>(tag: u8, block_hash: &Bh, latest_snapshot_id: Option<&Id>) -> Vec<u8> {
+    let mut record = vec![tag];
+    block_hash.encode(&mut record);
+    encode_option_ref(latest_snapshot_id, &mut record);
+    record
+}
+
+fn decode_record<Bh, S>(input: &mut &[u8]) -> io::Result<JournalRecord<Bh, S>>
+    where
+        Bh: JournalCodec,
+        S: Snapshot + JournalCodec,
+        S::Id: JournalCodec,
+{
+    match take(input, 1)?[0] {
+        HEADER_TAG => Ok(JournalRecord::Header {
+            last_finalized_block: Option::decode(input)?,
+            latest_snapshot_id: Option::decode(input)?,
+        }),
+        SNAPSHOT_ADDED_TAG => Ok(JournalRecord::SnapshotAdded {
+            parent_block_hash: Bh::decode(input)?,
+            block_hash: Bh::decode(input)?,
+            snapshot: S::decode(input)?,
+            latest_snapshot_id: Option::decode(input)?,
+        }),
+        FINALIZED_TAG => Ok(JournalRecord::Finalized {
+            block_hash: Bh::decode(input)?,
+            latest_snapshot_id: Option::decode(input)?,
+        }),
+        DISCARDED_TAG => Ok(JournalRecord::Discarded {
+            block_hash: Bh::decode(input)?,
+            latest_snapshot_id: Option::decode(input)?,
+        }),
+        tag => Err(invalid_data(format!("unexpected journal record tag {}", tag))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codec_roundtrip() {
+        let mut out = Vec::new();
+        42u64.encode(&mut out);
+        "block".to_string().encode(&mut out);
+        Some("x".to_string()).encode(&mut out);
+        Option::<u64>::None.encode(&mut out);
+
+        let mut input = &out[..];
+        assert_eq!(42, u64::decode(&mut input).unwrap());
+        assert_eq!("block", String::decode(&mut input).unwrap());
+        assert_eq!(Some("x".to_string()), Option::<String>::decode(&mut input).unwrap());
+        assert_eq!(None, Option::<u64>::decode(&mut input).unwrap());
+        assert!(input.is_empty());
+        assert_eq!(io::ErrorKind::UnexpectedEof, u64::decode(&mut input).unwrap_err().kind());
+    }
+}
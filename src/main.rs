@@ -1,29 +1,34 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 
-use std::collections::HashMap;
-use std::fmt::Display;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Debug, Display};
 use std::hash::Hash;
-use std::sync::{Arc, Mutex, RwLock};
-use crate::block_state_manager::{BlockStateManager, Snapshot, TreeQuery};
+use std::sync::{Arc, Mutex};
+use crate::block_state_manager::{BlockStateManager, BlockStateManagerError, BlockStateManagerHandle, Snapshot, SnapshotIdentity, TreeQuery};
 use crate::db::{Database, Storage};
 use crate::rollup_interface::{STF};
 use crate::stf::{Operation, SampleSTF};
 use crate::types::{Key, Value};
 
+mod bloom;
 mod db;
+mod journal;
+mod metrics;
 mod witness;
 mod state;
 mod block_state_manager;
 mod stf;
 mod types;
 mod rollup_interface;
+#[cfg(test)]
+mod test_utils;
 
 pub type BlockHash = String;
 
 fn runner<Stf, P, S, B, Bh>(
     mut stf: Stf,
-    block_state_manager: Arc<RwLock<BlockStateManager<P, S, Bh>>>,
+    block_state_manager: BlockStateManagerHandle<P, S, Bh>,
 
 
     // Simulates arrival of DA blocks
@@ -34,33 +39,77 @@ fn runner<Stf, P, S, B, Bh>(
     mut batches: HashMap<Bh, Vec<(Bh, Vec<B>)>>)
     where
     // This constraint is for a map.
-        Bh: Eq + Hash + Clone + Display,
+        Bh: Eq + Hash + Clone + Display + Debug,
         P: Storage<Key=S::Key, Value=S::Value>,
         S: Snapshot + Into<P::Payload>,
+        S::Id: SnapshotIdentity<Bh>,
         Stf: STF<BlobTransaction=B, ChangeSet=S, SnapshotRef=TreeQuery<P, BlockStateManager<P, S, Bh>>>,
             {
                 assert_eq!(chain.len(), finalized_blocks.len());
-                for (current_block_hash, finalized_block_hash) in chain.into_iter().zip(finalized_blocks.into_iter()) {
-                    println!("== Iterating over current block {}", current_block_hash);
-                    let forks = batches.remove(&current_block_hash).unwrap_or_default();
-                    for (child_block_hash, blob) in forks {
-                        println!("Executing fork from prev={} to next={}", current_block_hash, child_block_hash);
-                        let snapshot_ref = {
-                            let mut fm = block_state_manager.write().unwrap();
-                            fm.get_new_ref(&current_block_hash, &child_block_hash)
+                let mut chain = chain.into_iter();
+                let mut finalized_blocks = finalized_blocks.into_iter();
+                // Forks of the last pulled block, which are not accepted yet. First one is blocked by back pressure, if any
+                let mut forks: VecDeque<(Bh, Bh, Vec<B>)> = VecDeque::new();
+                loop {
+                    // Next block is pulled only after all forks of the previous one are accepted,
+                    // so back pressure holds the source instead of piling its blocks up here
+                    if forks.is_empty() {
+                        let Some(current_block_hash) = chain.next() else {
+                            break;
+                        };
+                        println!("== Iterating over current block {}", current_block_hash);
+                        let current_forks = batches.remove(&current_block_hash).unwrap_or_default();
+                        forks.extend(current_forks.into_iter().map(|(child_block_hash, blob)| (current_block_hash.clone(), child_block_hash, blob)));
+                    }
+                    while let Some((prev_block_hash, child_block_hash, blob)) = forks.pop_front() {
+                        println!("Executing fork from prev={} to next={}", prev_block_hash, child_block_hash);
+                        let snapshot_ref = match block_state_manager.get_new_ref(&prev_block_hash, &child_block_hash) {
+                            Ok(snapshot_ref) => snapshot_ref,
+                            Err(BlockStateManagerError::BackPressure(usage)) => {
+                                println!("Waiting for finalization, {} blocks and {} bytes are pending", usage.blocks, usage.bytes);
+                                forks.push_front((prev_block_hash, child_block_hash, blob));
+                                break;
+                            }
+                            Err(e) => {
+                                println!("Skipping fork to {}: {}", child_block_hash, e);
+                                continue;
+                            }
                         };
                         let (_witness, snapshot) = stf.apply_slot(snapshot_ref, blob);
-                        {
-                            let mut fm = block_state_manager.write().unwrap();
-                            fm.add_snapshot(snapshot);
+                        if let Err(e) = block_state_manager.add_snapshot(snapshot) {
+                            println!("Failed to add snapshot for {}: {}", child_block_hash, e);
                         }
                     }
+                    // Finality keeps arriving while source is held
+                    let Some(finalized_block_hash) = finalized_blocks.next() else {
+                        break;
+                    };
                     if let Some(finalized_block_hash) = finalized_block_hash {
-                        let mut fm = block_state_manager.write().unwrap();
-                        fm.finalize_snapshot(&finalized_block_hash);
+                        // Finality of the block implies finality of all its ancestors
+                        if let Err(e) = block_state_manager.finalize_up_to(&finalized_block_hash) {
+                            println!("Failed to finalize {}: {}", finalized_block_hash, e);
+                        }
                     }
+                    // Blocks executed while journal is behind would be lost on restart, so it is repaired before pulling more
+                    if let Some(kind) = block_state_manager.journal_failure() {
+                        println!("Journal write has failed: {}, repairing", kind);
+                        if let Err(e) = block_state_manager.repair_journal() {
+                            println!("Stopping, journal cannot be repaired: {}", e);
+                            break;
+                        }
+                    }
+                    print!("{}", block_state_manager.read().unwrap().render_ascii());
+                    let usage = block_state_manager.usage();
+                    println!("Pending: {} blocks, {} bytes", usage.blocks, usage.bytes);
                     println!("== ========");
                 }
+                // Nothing is going to be finalized anymore, so they cannot be accepted
+                for (prev_block_hash, child_block_hash, _) in forks {
+                    println!("Fork from prev={} to next={} has not been executed because of back pressure", prev_block_hash, child_block_hash);
+                }
+                for current_block_hash in chain {
+                    println!("Block {} has not been pulled because of back pressure", current_block_hash);
+                }
             }
 
             macro_rules! hashmap {
@@ -76,7 +125,7 @@ fn main() {
     let stf: SampleSTF<Database, BlockHash> = SampleSTF::new(db.clone());
 
     // Bootstrap fork_state_manager
-    let block_state_manager = BlockStateManager::new_locked(db.clone());
+    let block_state_manager = BlockStateManagerHandle::new(db.clone());
 
 
     // Current chain
@@ -0,0 +1,121 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Receiver of measurements of the state layer. All methods do nothing by default,
+/// so implementation can pick only what it is interested in.
+pub trait Metrics: Debug + Send + Sync {
+    /// Number of snapshots in the manager, after it has changed
+    fn pending_snapshots(&self, _count: usize) {}
+
+    /// New block has been registered on top of its parent
+    fn fork_created(&self) {}
+
+    fn blocks_finalized(&self, _count: usize) {}
+
+    /// Blocks removed without being committed: reorged out or competing with finalized ones
+    fn blocks_discarded(&self, _count: usize) {}
+
+    /// Value has been found in the pending snapshot, `depth` is 1 for the direct parent,
+    /// or 0 for the snapshot of the block itself, when state after it is read
+    fn cache_layer_hit(&self, _depth: usize) {}
+
+    /// Value has not been found in pending snapshots, so storage has been queried
+    fn storage_fallback(&self) {}
+
+    fn working_set_read(&self) {}
+
+    fn working_set_write(&self) {}
+
+    fn working_set_revert(&self) {}
+
+    /// Recorded by [`crate::db::MeteredStorage`], so commits of every storage user are measured
+    fn storage_commit(&self, _keys_written: usize, _duration: Duration) {}
+}
+
+/// Default [`Metrics`], which discards everything.
+#[derive(Debug, Default)]
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {}
+
+/// Everything, that has been recorded by [`InMemoryMetrics`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RecordedMetrics {
+    pub pending_snapshots: usize,
+    pub forks_created: usize,
+    pub blocks_finalized: usize,
+    pub blocks_discarded: usize,
+    /// Depth -> number of hits
+    pub cache_layer_hits: BTreeMap<usize, usize>,
+    pub storage_fallbacks: usize,
+    pub working_set_reads: usize,
+    pub working_set_writes: usize,
+    pub working_set_reverts: usize,
+    pub storage_commits: usize,
+    pub keys_committed: usize,
+    pub commit_duration: Duration,
+}
+
+/// Keeps counters in memory, mostly for tests.
+#[derive(Debug, Default)]
+pub struct InMemoryMetrics {
+    recorded: Mutex<RecordedMetrics>,
+}
+
+impl InMemoryMetrics {
+    pub fn recorded(&self) -> RecordedMetrics {
+        self.recorded.lock().unwrap().clone()
+    }
+
+    fn record(&self, f: impl FnOnce(&mut RecordedMetrics)) {
+        f(&mut self.recorded.lock().unwrap());
+    }
+}
+
+impl Metrics for InMemoryMetrics {
+    fn pending_snapshots(&self, count: usize) {
+        self.record(|recorded| recorded.pending_snapshots = count);
+    }
+
+    fn fork_created(&self) {
+        self.record(|recorded| recorded.forks_created += 1);
+    }
+
+    fn blocks_finalized(&self, count: usize) {
+        self.record(|recorded| recorded.blocks_finalized += count);
+    }
+
+    fn blocks_discarded(&self, count: usize) {
+        self.record(|recorded| recorded.blocks_discarded += count);
+    }
+
+    fn cache_layer_hit(&self, depth: usize) {
+        self.record(|recorded| *recorded.cache_layer_hits.entry(depth).or_default() += 1);
+    }
+
+    fn storage_fallback(&self) {
+        self.record(|recorded| recorded.storage_fallbacks += 1);
+    }
+
+    fn working_set_read(&self) {
+        self.record(|recorded| recorded.working_set_reads += 1);
+    }
+
+    fn working_set_write(&self) {
+        self.record(|recorded| recorded.working_set_writes += 1);
+    }
+
+    fn working_set_revert(&self) {
+        self.record(|recorded| recorded.working_set_reverts += 1);
+    }
+
+    fn storage_commit(&self, keys_written: usize, duration: Duration) {
+        self.record(|recorded| {
+            recorded.storage_commits += 1;
+            recorded.keys_committed += keys_written;
+            recorded.commit_duration += duration;
+        });
+    }
+}
@@ -1,3 +1,4 @@
+#[allow(clippy::upper_case_acronyms)]
 pub trait STF {
     type Witness;
     type BlobTransaction;
@@ -6,7 +7,7 @@ pub trait STF {
     type ChangeSet;
 
 
-    fn apply_slot<'a, I>(
+    fn apply_slot<I>(
         &mut self,
         base: Self::SnapshotRef,
         blobs: I,
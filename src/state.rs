@@ -1,10 +1,14 @@
 use std::collections::HashMap;
-use std::fmt::{Debug, Formatter};
+use std::hash::Hash;
+use std::fmt::{Debug, Display, Formatter};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 use sov_first_read_last_write_cache::cache::{CacheLog, ValueExists};
 use sov_first_read_last_write_cache::{CacheKey, CacheValue};
-use crate::block_state_manager::{QueryParents, Snapshot, SnapshotId, TreeQuery};
+use crate::block_state_manager::{BranchToken, QueryError, QueryParents, Snapshot, SnapshotId, TreeQuery};
+use crate::bloom::KeyFilter;
 use crate::db::{Database, Storage};
+use crate::journal::{decode_bytes, encode_bytes, JournalCodec};
 use crate::types::{Key, Value};
 use crate::witness::Witness;
 
@@ -14,40 +18,104 @@ pub type DB = Arc<Mutex<Database>>;
 /// Represent CacheLayer that can be used in 2 ways:
 ///  - query own value
 ///  - be saved to database
-pub struct FrozenSnapshot {
-    id: SnapshotId,
-    local_cache: CacheLog,
+pub struct FrozenSnapshot<Id = SnapshotId> {
+    id: Id,
+    // Only writes are kept, reads are served by parents or database
+    local_cache: HashMap<CacheKey, Option<CacheValue>>,
+    // Built once snapshot is frozen, allows to skip it without looking into the cache
+    filter: Option<KeyFilter>,
+    // Token of the block registration, is not journaled, as recovered snapshots are not added again
+    branch: BranchToken,
 }
 
-impl Debug for FrozenSnapshot {
+impl<Id> FrozenSnapshot<Id> {
+    /// Drops key filter, so every read looks into the cache. Mostly for comparing read performance.
+    pub fn without_filter(mut self) -> Self {
+        self.filter = None;
+        self
+    }
+}
+
+impl<Id: Debug> Debug for FrozenSnapshot<Id> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "FrozenSnapshot<Id={:?}>", self.get_id())
+        write!(f, "FrozenSnapshot<Id={:?}>", self.id)
     }
 }
 
-impl Snapshot for FrozenSnapshot {
+impl<Id: Eq + Hash + Clone + Debug> Snapshot for FrozenSnapshot<Id> {
     type Key = CacheKey;
     type Value = CacheValue;
+    type Id = Id;
 
-    fn get_value(&self, key: &Self::Key) -> Option<Self::Value> {
-        match self.local_cache.get_value(key) {
-            ValueExists::Yes(value) => {
-                value
-            }
-            ValueExists::No => {
-                None
-            }
+    fn get_value(&self, key: &Self::Key) -> Option<Option<Self::Value>> {
+        self.local_cache.get(key).cloned()
+    }
+
+    fn get_id(&self) -> Id {
+        self.id.clone()
+    }
+
+    fn may_contain(&self, key: &Self::Key) -> bool {
+        self.filter.as_ref().is_none_or(|filter| filter.may_contain(key))
+    }
+
+    fn writes_count(&self) -> usize {
+        self.local_cache.len()
+    }
+
+    fn size_in_bytes(&self) -> usize {
+        self.local_cache.iter()
+            .map(|(key, value)| key.key.len() + value.as_ref().map_or(0, |value| value.value.len()))
+            .sum()
+    }
+
+    fn written_keys(&self) -> Vec<Self::Key> {
+        let mut written_keys: Vec<_> = self.local_cache.keys().cloned().collect();
+        written_keys.sort();
+        written_keys
+    }
+
+    fn branch_token(&self) -> BranchToken {
+        self.branch.clone()
+    }
+}
+
+impl<Id: JournalCodec> JournalCodec for FrozenSnapshot<Id> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.id.encode(out);
+        (self.local_cache.len() as u64).encode(out);
+        for (key, value) in &self.local_cache {
+            encode_bytes(&key.key, out);
+            value.as_ref().map(|value| value.value.to_vec()).encode(out);
         }
     }
 
-    fn get_id(&self) -> SnapshotId {
-        self.id
+    fn decode(input: &mut &[u8]) -> std::io::Result<Self> {
+        let id = Id::decode(input)?;
+        let len = u64::decode(input)?;
+        let mut local_cache = HashMap::new();
+        for _ in 0..len {
+            let key = CacheKey { key: Arc::new(decode_bytes(input)?) };
+            let value = Option::<Vec<u8>>::decode(input)?.map(|value| CacheValue { value: Arc::new(value) });
+            local_cache.insert(key, value);
+        }
+        let filter = KeyFilter::from_keys(local_cache.keys());
+        Ok(Self {
+            id,
+            local_cache,
+            filter: Some(filter),
+            branch: BranchToken::default(),
+        })
     }
 }
 
-impl From<FrozenSnapshot> for CacheLog {
-    fn from(value: FrozenSnapshot) -> Self {
-        value.local_cache
+impl<Id> From<FrozenSnapshot<Id>> for CacheLog {
+    fn from(value: FrozenSnapshot<Id>) -> Self {
+        let mut cache_log = CacheLog::with_capacity(value.local_cache.len());
+        for (key, value) in value.local_cache {
+            cache_log.add_write(key, value);
+        }
+        cache_log
     }
 }
 
@@ -69,23 +137,24 @@ impl Storage for Database {
     }
 
     fn get(&self, key: &Self::Key) -> Option<Self::Value> {
-        let key_string = key.to_string();
+        let key_string = Key::from(key.clone()).to_string();
         self.data.get(&key_string).map(|v| CacheValue::from(Value::from(v.clone())))
     }
 }
 
 /// Note: S: Snapshot can be inside storage spec, together with SnapshotId, and SnapshotId is DaSpec::BlockHash
-pub struct StateCheckpoint<P: Storage<Key=CacheKey, Value=CacheValue>, Q: QueryParents<Snapshot=FrozenSnapshot>> {
+pub struct StateCheckpoint<P: Storage<Key=CacheKey, Value=CacheValue>, Q: QueryParents> {
     cache: CacheLog,
-    witness: Witness,
+    witness: Witness<<Q::Snapshot as Snapshot>::Id>,
     parent: TreeQuery<P, Q>,
 }
 
 
-impl<P, Q> StateCheckpoint<P, Q>
+impl<P, Q, Id> StateCheckpoint<P, Q>
     where
         P: Storage<Key=CacheKey, Value=CacheValue>,
-        Q: QueryParents<Snapshot=FrozenSnapshot>,
+        Q: QueryParents<Snapshot=FrozenSnapshot<Id>>,
+        Id: Eq + Hash + Clone + Debug,
 {
     pub fn new(parent: TreeQuery<P, Q>) -> Self {
         Self {
@@ -95,6 +164,11 @@ impl<P, Q> StateCheckpoint<P, Q>
         }
     }
 
+    /// Block has been pruned, so there is no point to continue execution
+    pub fn is_cancelled(&self) -> bool {
+        self.parent.is_cancelled()
+    }
+
     pub fn into_revertable(self) -> WorkingSet<P, Q> {
         WorkingSet {
             cache: RevertableWriter::new(self.cache),
@@ -103,11 +177,15 @@ impl<P, Q> StateCheckpoint<P, Q>
         }
     }
 
-    pub fn freeze(mut self) -> (Witness, FrozenSnapshot) {
+    pub fn freeze(mut self) -> (Witness<Id>, FrozenSnapshot<Id>) {
         let witness = std::mem::take(&mut self.witness);
+        let local_cache: HashMap<_, _> = self.cache.take_writes().into_iter().collect();
+        let filter = KeyFilter::from_keys(local_cache.keys());
         let snapshot = FrozenSnapshot {
             id: self.parent.get_id(),
-            local_cache: self.cache,
+            local_cache,
+            filter: Some(filter),
+            branch: self.parent.branch_token(),
         };
 
         (witness, snapshot)
@@ -146,70 +224,223 @@ impl StateReaderAndWriter for CacheLog {
 }
 
 
-struct RevertableWriter<T> {
-    inner: T,
+/// Handle of a savepoint inside [`WorkingSet`]. It is consumed by rollback or release,
+/// and becomes invalid, when one of the enclosing savepoints is rolled back or released.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Savepoint {
+    // Writer, which has issued the savepoint
+    owner: u64,
+    id: u64,
+    // Position of its layer in the stack
+    depth: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SavepointError {
+    /// Savepoint has been dropped together with an enclosing one
+    Invalid,
+    /// Savepoint has been issued by another [`WorkingSet`]
+    Foreign,
+}
+
+impl Display for SavepointError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SavepointError::Invalid => write!(f, "savepoint has been already rolled back or released"),
+            SavepointError::Foreign => write!(f, "savepoint belongs to another working set"),
+        }
+    }
+}
+
+impl std::error::Error for SavepointError {}
+
+// Identity of each `RevertableWriter`, so their savepoints cannot be mixed up
+static NEXT_WRITER_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Changes of a single transaction on top of `inner`, which reach it only on commit.
+struct RevertableWriter {
+    id: u64,
+    inner: CacheLog,
+    // Values this transaction has read from outside, so repeated reads do not go to parents again.
+    // They survive rollback of savepoints, and reach `inner` even if transaction is reverted
+    reads: HashMap<CacheKey, Option<CacheValue>>,
+    // `None` is deletion
     writes: HashMap<CacheKey, Option<CacheValue>>,
+    // Writes done after each open savepoint, from the outermost one
+    savepoints: Vec<(u64, HashMap<CacheKey, Option<CacheValue>>)>,
+    next_savepoint_id: u64,
 }
 
 
-impl<T> RevertableWriter<T>
-    where
-        T: StateReaderAndWriter,
-{
-    fn new(inner: T) -> Self {
+impl RevertableWriter {
+    fn new(inner: CacheLog) -> Self {
         Self {
+            id: NEXT_WRITER_ID.fetch_add(1, Ordering::Relaxed),
             inner,
+            reads: Default::default(),
             writes: Default::default(),
+            savepoints: Vec::new(),
+            next_savepoint_id: 0,
         }
     }
 
-    fn commit(mut self) -> T {
-        for (k, v) in self.writes.into_iter() {
-            if let Some(v) = v {
-                self.inner.set(&k.into(), v.into());
-            } else {
-                self.inner.delete(&k.into());
-            }
+    /// Value written or read by this transaction, `Some(None)` if it is deleted or missing.
+    fn get(&self, key: &CacheKey) -> Option<Option<CacheValue>> {
+        self.savepoints.iter().rev()
+            .find_map(|(_, writes)| writes.get(key))
+            .or_else(|| self.writes.get(key))
+            .or_else(|| self.reads.get(key))
+            .cloned()
+    }
+
+    fn add_read(&mut self, key: CacheKey, value: Option<CacheValue>) {
+        self.reads.insert(key, value);
+    }
+
+    fn set(&mut self, key: CacheKey, value: CacheValue) {
+        self.current_writes().insert(key, Some(value));
+    }
+
+    fn delete(&mut self, key: CacheKey) {
+        self.current_writes().insert(key, None);
+    }
+
+    fn current_writes(&mut self) -> &mut HashMap<CacheKey, Option<CacheValue>> {
+        match self.savepoints.last_mut() {
+            Some((_, writes)) => writes,
+            None => &mut self.writes,
+        }
+    }
+
+    fn savepoint(&mut self) -> Savepoint {
+        let id = self.next_savepoint_id;
+        self.next_savepoint_id += 1;
+        self.savepoints.push((id, HashMap::new()));
+        Savepoint {
+            owner: self.id,
+            id,
+            depth: self.savepoints.len() - 1,
+        }
+    }
+
+    /// Drops writes done after the savepoint, including nested savepoints.
+    fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), SavepointError> {
+        self.check(&savepoint)?;
+        self.savepoints.truncate(savepoint.depth);
+        Ok(())
+    }
+
+    /// Keeps writes done after the savepoint, as part of the enclosing savepoint or transaction.
+    fn release(&mut self, savepoint: Savepoint) -> Result<(), SavepointError> {
+        self.check(&savepoint)?;
+        for (_, writes) in self.savepoints.split_off(savepoint.depth) {
+            self.current_writes().extend(writes);
+        }
+        Ok(())
+    }
+
+    fn check(&self, savepoint: &Savepoint) -> Result<(), SavepointError> {
+        if savepoint.owner != self.id {
+            return Err(SavepointError::Foreign);
+        }
+        match self.savepoints.get(savepoint.depth) {
+            Some((id, _)) if *id == savepoint.id => Ok(()),
+            _ => Err(SavepointError::Invalid),
+        }
+    }
+
+    /// Savepoints, which are still open, are released.
+    fn commit(mut self) -> CacheLog {
+        let mut writes = std::mem::take(&mut self.writes);
+        for (_, savepoint_writes) in std::mem::take(&mut self.savepoints) {
+            writes.extend(savepoint_writes);
+        }
+        let mut inner = self.apply_reads();
+        for (k, v) in writes {
+            inner.add_write(k, v);
         }
+        inner
+    }
 
-        self.inner
+    /// Reads are kept: they describe parent state, which does not depend on the transaction outcome.
+    fn revert(self) -> CacheLog {
+        self.apply_reads()
     }
 
-    fn revert(self) -> T {
-        self.inner
+    fn apply_reads(self) -> CacheLog {
+        let mut inner = self.inner;
+        for (k, v) in self.reads {
+            // Only keys missing in `inner` are read from outside, so there is nothing to contradict
+            inner.add_read(k, v).expect("read of key missing in the cache log cannot be inconsistent");
+        }
+        inner
     }
 }
 
-pub struct WorkingSet<P: Storage<Key=CacheKey, Value=CacheValue>, Q: QueryParents<Snapshot=FrozenSnapshot>> {
-    cache: RevertableWriter<CacheLog>,
-    witness: Witness,
+pub struct WorkingSet<P: Storage<Key=CacheKey, Value=CacheValue>, Q: QueryParents> {
+    cache: RevertableWriter,
+    witness: Witness<<Q::Snapshot as Snapshot>::Id>,
     parent: TreeQuery<P, Q>,
 }
 
-impl<P, Q> WorkingSet<P, Q>
+impl<P, Q, Id> WorkingSet<P, Q>
     where
         P: Storage<Key=CacheKey, Value=CacheValue>,
-        Q: QueryParents<Snapshot=FrozenSnapshot>,
+        Q: QueryParents<Snapshot=FrozenSnapshot<Id>>,
+        Id: Eq + Hash + Clone + Debug,
 {
     /// Public interface. Reads local cache, then tries parents and then database, if parent was committed
-    pub fn get(&mut self, key: &Key) -> Option<Value> {
+    /// Fails if block has been orphaned, as its parents cannot be trusted anymore
+    pub fn get(&mut self, key: &Key) -> Result<Option<Value>, QueryError<Id>> {
+        self.parent.metrics().working_set_read();
         let cache_key = CacheKey::from(key.clone());
-        let value = self.cache.inner.get(key);
-        if value.is_some() {
-            return value;
+        if let Some(value) = self.cache.get(&cache_key) {
+            return Ok(value.map(Value::from));
+        }
+        // Deleted by previous transaction is not the same as missing
+        if let ValueExists::Yes(value) = self.cache.inner.get_value(&cache_key) {
+            return Ok(value.map(Value::from));
         }
 
-        let cache_value = self.parent.get_value_from_cache_layers(&cache_key);
-        self.cache.writes.insert(cache_key, cache_value.clone());
+        let (cache_value, source) = self.parent.get_value_from_cache_layers(&cache_key)?;
+        self.cache.add_read(cache_key, cache_value.clone());
         let value = cache_value.map(Value::from);
-        self.witness.track_operation(key, value.clone());
-        value
+        // Repeated reads are served by the cache above, so only the first one gets here
+        self.witness.track_read(key, value.clone(), source);
+        Ok(value)
     }
 
 
+    /// Block has been pruned, so transaction can be aborted early
+    pub fn is_cancelled(&self) -> bool {
+        self.parent.is_cancelled()
+    }
+
     pub fn set(&mut self, key: &Key, value: Value) {
-        self.witness.track_operation(key, Some(value.clone()));
-        self.cache.inner.set(key, value);
+        self.parent.metrics().working_set_write();
+        self.cache.set(CacheKey::from(key.clone()), CacheValue::from(value));
+    }
+
+    /// Tombstone is kept until commit, so key reads as `None` even if parents or storage have it.
+    pub fn delete(&mut self, key: &Key) {
+        self.parent.metrics().working_set_write();
+        self.cache.delete(CacheKey::from(key.clone()));
+    }
+
+    /// Marks the point, which changes can be rolled back to without reverting the whole transaction.
+    /// Savepoints can be nested.
+    pub fn savepoint(&mut self) -> Savepoint {
+        self.cache.savepoint()
+    }
+
+    /// Discards writes done after the savepoint. Reads are kept in the witness, as they have happened.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), SavepointError> {
+        self.cache.rollback_to(savepoint)
+    }
+
+    /// Merges writes done after the savepoint into the enclosing savepoint or transaction.
+    pub fn release(&mut self, savepoint: Savepoint) -> Result<(), SavepointError> {
+        self.cache.release(savepoint)
     }
 
 
@@ -222,10 +453,100 @@ impl<P, Q> WorkingSet<P, Q>
     }
 
     pub fn revert(self) -> StateCheckpoint<P, Q> {
+        self.parent.metrics().working_set_revert();
         StateCheckpoint {
             cache: self.cache.revert(),
-            witness: Witness::default(),
+            witness: self.witness,
             parent: self.parent,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::block_state_manager::{BlockStateManagerHandle, ValueSource};
+    use crate::test_utils::{cache_key, key, value};
+    use super::*;
+
+    #[test]
+    fn reverted_transaction_leaves_no_trace() {
+        let db = DB::default();
+        db.lock().unwrap().set("y", "0".to_string());
+        let state_manager = BlockStateManagerHandle::<Database, FrozenSnapshot, String>::new(db.clone());
+        let snapshot_ref = state_manager.get_new_ref(&"genesis".to_string(), &"a".to_string()).unwrap();
+
+        let mut working_set = StateCheckpoint::new(snapshot_ref).into_revertable();
+        working_set.set(&key("x"), value("1"));
+        working_set.delete(&key("y"));
+        assert_eq!(Ok(Some(value("1"))), working_set.get(&key("x")));
+        assert_eq!(Ok(None), working_set.get(&key("y")));
+        let mut working_set = working_set.revert().into_revertable();
+
+        assert_eq!(Ok(None), working_set.get(&key("x")));
+        assert_eq!(Ok(Some(value("0"))), working_set.get(&key("y")));
+        working_set.set(&key("z"), value("2"));
+        let (_witness, snapshot) = working_set.commit().freeze();
+
+        assert_eq!(vec![cache_key("z")], snapshot.written_keys());
+    }
+
+    #[test]
+    fn nested_savepoints() {
+        let db = DB::default();
+        db.lock().unwrap().set("r", "0".to_string());
+        let state_manager = BlockStateManagerHandle::<Database, FrozenSnapshot, String>::new(db.clone());
+        let snapshot_ref = state_manager.get_new_ref(&"genesis".to_string(), &"a".to_string()).unwrap();
+
+        let mut working_set = StateCheckpoint::new(snapshot_ref).into_revertable();
+        working_set.set(&key("x"), value("1"));
+        let outer = working_set.savepoint();
+        working_set.set(&key("x"), value("2"));
+        working_set.set(&key("y"), value("2"));
+        let inner = working_set.savepoint();
+        working_set.delete(&key("y"));
+        assert_eq!(Ok(Some(value("0"))), working_set.get(&key("r")));
+        assert_eq!(Ok(None), working_set.get(&key("y")));
+        working_set.rollback_to(inner).unwrap();
+        assert_eq!(Ok(Some(value("2"))), working_set.get(&key("y")));
+
+        let inner = working_set.savepoint();
+        working_set.set(&key("z"), value("3"));
+        working_set.release(inner).unwrap();
+        assert_eq!(Ok(Some(value("3"))), working_set.get(&key("z")));
+
+        let stale = working_set.savepoint();
+        working_set.rollback_to(outer).unwrap();
+        assert_eq!(Err(SavepointError::Invalid), working_set.rollback_to(stale));
+        // Read inside rolled back savepoint is still in the witness
+        let witness: Vec<_> = working_set.witness.entries().into_iter()
+            .map(|(key, value, source)| (key.to_string(), value, source))
+            .collect();
+        assert_eq!(vec![("r".to_string(), Some(value("0")), ValueSource::Storage)], witness);
+        assert_eq!(Ok(Some(value("1"))), working_set.get(&key("x")));
+        assert_eq!(Ok(None), working_set.get(&key("y")));
+        assert_eq!(Ok(None), working_set.get(&key("z")));
+
+        // Open savepoints are released on commit
+        let _open = working_set.savepoint();
+        working_set.set(&key("w"), value("4"));
+        let (_witness, snapshot) = working_set.commit().freeze();
+        assert_eq!(vec![cache_key("w"), cache_key("x")], snapshot.written_keys());
+    }
+
+    #[test]
+    fn savepoint_of_another_working_set() {
+        let db = DB::default();
+        let state_manager = BlockStateManagerHandle::<Database, FrozenSnapshot, String>::new(db.clone());
+        let snapshot_ref_a = state_manager.get_new_ref(&"genesis".to_string(), &"a".to_string()).unwrap();
+        let snapshot_ref_b = state_manager.get_new_ref(&"genesis".to_string(), &"b".to_string()).unwrap();
+
+        let mut working_set_a = StateCheckpoint::new(snapshot_ref_a).into_revertable();
+        let mut working_set_b = StateCheckpoint::new(snapshot_ref_b).into_revertable();
+        let savepoint_a = working_set_a.savepoint();
+        let savepoint_b = working_set_b.savepoint();
+        working_set_b.set(&key("x"), value("1"));
+        assert_eq!(Err(SavepointError::Foreign), working_set_b.rollback_to(savepoint_a));
+        assert_eq!(Ok(Some(value("1"))), working_set_b.get(&key("x")));
+        assert_eq!(Err(SavepointError::Foreign), working_set_a.release(savepoint_b));
+    }
+}
@@ -1,8 +1,9 @@
+use std::fmt::Debug;
 use std::hash::Hash;
 use std::marker::PhantomData;
 use sov_first_read_last_write_cache::cache::CacheLog;
 use sov_first_read_last_write_cache::{CacheKey, CacheValue};
-use crate::block_state_manager::{BlockStateManager, TreeQuery};
+use crate::block_state_manager::{BlockStateManager, SnapshotId, SnapshotIdentity, TreeQuery};
 use crate::db::Storage;
 use crate::rollup_interface::STF;
 use crate::state::{DB, FrozenSnapshot, StateCheckpoint};
@@ -13,17 +14,19 @@ use crate::witness::Witness;
 pub enum Operation {
     Get(Key),
     Set(Key, Value),
+    Delete(Key),
 }
 
 
-pub struct SampleSTF<P: Storage<Payload=CacheLog>, Bh> {
+pub struct SampleSTF<P: Storage<Payload=CacheLog>, Bh, Id = SnapshotId> {
     phantom_persistence: PhantomData<P>,
     phantom_bh: PhantomData<Bh>,
+    phantom_id: PhantomData<Id>,
     // TODO: Should be read only db
     db: DB,
 }
 
-impl<P, Bh> SampleSTF<P, Bh>
+impl<P, Bh, Id> SampleSTF<P, Bh, Id>
     where
         P: Storage<Payload=CacheLog, Key=CacheKey, Value=CacheValue>,
 {
@@ -31,54 +34,72 @@ impl<P, Bh> SampleSTF<P, Bh>
         Self {
             phantom_persistence: PhantomData,
             phantom_bh: PhantomData,
+            phantom_id: PhantomData,
             db,
         }
     }
 }
 
 //
-impl<P, Bh> SampleSTF<P, Bh>
+impl<P, Bh, Id> SampleSTF<P, Bh, Id>
     where
         P: Storage<Payload=CacheLog, Key=CacheKey, Value=CacheValue>,
         Bh: Eq + Hash + Clone,
+        Id: Eq + Hash + Clone + Debug + SnapshotIdentity<Bh>,
 {
-    fn apply_operation(&mut self, checkpoint: StateCheckpoint<P, BlockStateManager<P, FrozenSnapshot, Bh>>, operation: Operation) -> StateCheckpoint<P, BlockStateManager<P, FrozenSnapshot, Bh>> {
+    fn apply_operation(&mut self, checkpoint: StateCheckpoint<P, BlockStateManager<P, FrozenSnapshot<Id>, Bh>>, operation: Operation) -> StateCheckpoint<P, BlockStateManager<P, FrozenSnapshot<Id>, Bh>> {
         let mut working_set = checkpoint.into_revertable();
         match operation {
             Operation::Get(key) => {
-                let value = working_set.get(&key);
-                println!("Get {} {:?}", key.to_string(), value.map(|v| v.to_string()));
+                match working_set.get(&key) {
+                    Ok(value) => println!("Get {} {:?}", key, value.map(|v| v.to_string())),
+                    Err(e) => {
+                        println!("Reverting transaction: {}", e);
+                        return working_set.revert();
+                    }
+                }
             }
             Operation::Set(key, value) => {
                 let key_string = key.to_string();
                 let value_string = value.to_string();
-                // TODO: First try to read existing value, so we have a case of non polluting reads
                 if &key_string == "foo" && value_string == "bar" {
-                    println!("Skipping this transaction to previous state");
+                    // Read of reverted transaction should not end up in the snapshot
+                    if let Ok(existing) = working_set.get(&key) {
+                        println!("Skipping this transaction to previous state, keeping {:?}", existing.map(|v| v.to_string()));
+                    }
                     return working_set.revert();
                 }
-                println!("Set {} = {}", key.to_string(), value.to_string());
+                println!("Set {} = {}", key, value);
                 working_set.set(&key, value);
             }
+            Operation::Delete(key) => {
+                println!("Delete {}", key);
+                working_set.delete(&key);
+            }
         }
-        return working_set.commit();
+        working_set.commit()
     }
 }
 
 
-impl<P, Bh> STF for SampleSTF<P, Bh>
+impl<P, Bh, Id> STF for SampleSTF<P, Bh, Id>
     where
         P: Storage<Payload=CacheLog, Key=CacheKey, Value=CacheValue>,
-        Bh: Eq + Hash + Clone
+        Bh: Eq + Hash + Clone,
+        Id: Eq + Hash + Clone + Debug + SnapshotIdentity<Bh>,
 {
-    type Witness = Witness;
+    type Witness = Witness<Id>;
     type BlobTransaction = Operation;
-    type SnapshotRef = TreeQuery<P, BlockStateManager<P, FrozenSnapshot, Bh>>;
-    type ChangeSet = FrozenSnapshot;
+    type SnapshotRef = TreeQuery<P, BlockStateManager<P, FrozenSnapshot<Id>, Bh>>;
+    type ChangeSet = FrozenSnapshot<Id>;
 
-    fn apply_slot<'a, I>(&mut self, base: Self::SnapshotRef, blobs: I) -> (Self::Witness, Self::ChangeSet) where I: IntoIterator<Item=Self::BlobTransaction> {
+    fn apply_slot<I>(&mut self, base: Self::SnapshotRef, blobs: I) -> (Self::Witness, Self::ChangeSet) where I: IntoIterator<Item=Self::BlobTransaction> {
         let mut checkpoint = StateCheckpoint::new(base);
         for operation in blobs {
+            if checkpoint.is_cancelled() {
+                println!("Block has been pruned, aborting execution");
+                break;
+            }
             checkpoint = self.apply_operation(checkpoint, operation);
         }
 
@@ -88,3 +109,41 @@ impl<P, Bh> STF for SampleSTF<P, Bh>
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use crate::block_state_manager::{BlockStateManagerHandle, Snapshot};
+    use crate::db::Database;
+    use crate::BlockHash;
+    use crate::test_utils::{cache_key, key, value};
+    use super::*;
+
+    #[test]
+    fn skipped_transaction_does_not_leak() {
+        let db = Arc::new(Mutex::new(Database::default()));
+        db.lock().unwrap().set("foo", "baz".to_string());
+        db.lock().unwrap().set("z", "0".to_string());
+        let mut stf: SampleSTF<Database, BlockHash> = SampleSTF::new(db.clone());
+        let state_manager = BlockStateManagerHandle::new(db.clone());
+
+        let snapshot_ref = state_manager.get_new_ref(&"genesis".to_string(), &"a".to_string()).unwrap();
+        let (_witness, snapshot) = stf.apply_slot(snapshot_ref, vec![
+            Operation::Set(key("x"), value("1")),
+            Operation::Set(key("foo"), value("bar")),
+            Operation::Get(key("z")),
+            Operation::Set(key("y"), value("2")),
+        ]);
+
+        let mut written_keys: Vec<String> = snapshot.written_keys().into_iter().map(|k| Key::from(k).to_string()).collect();
+        written_keys.sort();
+        assert_eq!(vec!["x".to_string(), "y".to_string()], written_keys);
+        assert_eq!(None, snapshot.get_value(&cache_key("foo")));
+
+        state_manager.add_snapshot(snapshot).unwrap();
+        state_manager.finalize_snapshot(&"a".to_string()).unwrap();
+        assert_eq!(Some("baz".to_string()), db.lock().unwrap().get("foo"));
+        assert_eq!(Some("0".to_string()), db.lock().unwrap().get("z"));
+        assert_eq!(Some("1".to_string()), db.lock().unwrap().get("x"));
+    }
+}
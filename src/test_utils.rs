@@ -0,0 +1,29 @@
+//! Shorthands for building test data from string literals.
+
+use sov_first_read_last_write_cache::{CacheKey, CacheValue};
+use crate::BlockHash;
+use crate::types::{Key, Value};
+
+pub fn bh(block_hash: &str) -> BlockHash {
+    block_hash.to_string()
+}
+
+pub fn block_hashes(block_hashes: &[&str]) -> Vec<BlockHash> {
+    block_hashes.iter().map(|block_hash| bh(block_hash)).collect()
+}
+
+pub fn key(key: &str) -> Key {
+    Key::from(key.to_string())
+}
+
+pub fn value(value: &str) -> Value {
+    Value::from(value.to_string())
+}
+
+pub fn cache_key(key: &str) -> CacheKey {
+    CacheKey::from(self::key(key))
+}
+
+pub fn cache_value(value: &str) -> CacheValue {
+    CacheValue::from(self::value(value))
+}
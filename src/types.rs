@@ -1,3 +1,4 @@
+use std::fmt::{Display, Formatter};
 use std::sync::{Arc, LockResult, RwLock, RwLockReadGuard};
 use sov_first_read_last_write_cache::{CacheKey, CacheValue};
 
@@ -17,9 +18,9 @@ impl From<String> for Key {
     }
 }
 
-impl ToString for Key {
-    fn to_string(&self) -> String {
-        String::from_utf8_lossy(&self.key[..]).to_string()
+impl Display for Key {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.key[..]))
     }
 }
 
@@ -58,9 +59,9 @@ impl From<Value> for String {
     }
 }
 
-impl ToString for Value {
-    fn to_string(&self) -> String {
-        String::from_utf8_lossy(&self.value[..]).to_string()
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.value[..]))
     }
 }
 
@@ -2,16 +2,33 @@
 #![allow(unused_variables)]
 
 use std::cell::RefCell;
+use crate::block_state_manager::{SnapshotId, ValueSource};
 use crate::types::{Key, Value};
 
-#[derive(Default, Debug)]
-pub struct Witness {
-    data: RefCell<Vec<(Key, Option<Value>)>>,
+/// Values, which block has read from outside of its own cache: from parent snapshots or storage.
+/// Each key is recorded once, on the first read, together with the layer that served it; writes are not recorded.
+#[derive(Debug)]
+pub struct Witness<Id = SnapshotId> {
+    #[allow(clippy::type_complexity)]
+    data: RefCell<Vec<(Key, Option<Value>, ValueSource<Id>)>>,
 }
 
-impl Witness {
-    pub fn track_operation(&self, key: &Key, value: Option<Value>) {
-        self.data.borrow_mut().push((key.clone(), value));
+impl<Id> Default for Witness<Id> {
+    fn default() -> Self {
+        Self {
+            data: Default::default(),
+        }
+    }
+}
+
+impl<Id: Clone> Witness<Id> {
+    pub fn track_read(&self, key: &Key, value: Option<Value>, source: ValueSource<Id>) {
+        self.data.borrow_mut().push((key.clone(), value, source));
+    }
+
+    /// Recorded reads, in order
+    pub fn entries(&self) -> Vec<(Key, Option<Value>, ValueSource<Id>)> {
+        self.data.borrow().clone()
     }
 
     pub fn len(&self) -> usize {